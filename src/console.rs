@@ -0,0 +1,431 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! operator console of the persistent server mode
+//!
+//! the console reads commands with line editing and history, the history
+//! is kept in ~/.trsh_history. commands are run on the session selected
+//! with use <n>.
+use std::env;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+
+use crate::session::{Session, Sessions};
+use crate::terminal;
+use crate::{
+    handle_get_command, handle_get_tree_command, handle_os_command, handle_put_command,
+    handle_put_tree_command, parse_timeout, request_compression, run_shell, send_remote_command,
+    shell_request,
+};
+use cryptolib::mux::Channel;
+use cryptolib::protocol::{write_message, Compression, Message};
+
+const HELP: &str = "\
+sessions                          lists the connected sessions
+use <n>                           selects session n
+exec [-t <seconds>] <command>     executes command on the selected session, -t kills it
+                                  after the timeout, the default is the timeout of the
+                                  command line. ctrl-c kills the command, not the server
+get [-r] <source> <target dir>    transfers a file from the selected session, -r a
+                                  directory tree
+put [-r] <source> <target dir>    transfers a file to the selected session, -r a
+                                  directory tree. with -r, -i <glob> transfers only the
+                                  matching files and -x <glob> skips the matching
+                                  files and directories, both can be repeated
+                                  exec, get and put compress their data when the session
+                                  supports it, -z fails without compression and -Z sends
+                                  the data uncompressed
+shell [-c] [<program> [<args>]]   starts a shell on the selected session in raw mode, -c
+                                  keeps the terminal cooked. ctrl-] detaches back to the
+                                  console. without program the client falls back to
+                                  $SHELL, bash and sh
+kill <n>                          closes session n
+help                              shows this help
+quit                              stops the server";
+
+/// reads operator commands until quit or the end of the input, timeout is the
+/// default timeout of exec
+pub fn run_console(sessions: &Sessions, timeout: Option<Duration>) {
+    let mut editor = Editor::<()>::new();
+    let history = history_file();
+    if let Some(history) = &history {
+        let _ = editor.load_history(history);
+    }
+    println!("type help for the list of commands");
+
+    let mut selected: Option<usize> = None;
+    loop {
+        let prompt = match selected {
+            Some(number) => format!("trsh({})> ", number),
+            None => "trsh> ".to_string(),
+        };
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                println!("error reading console: {}", e);
+                break;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line);
+
+        let (command, arg) = match line.split_once(' ') {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+        let args: Vec<&str> = arg.split_whitespace().collect();
+
+        match command {
+            "help" => println!("{}", HELP),
+            "sessions" => list_sessions(sessions),
+            "use" => match arg.parse::<usize>() {
+                Ok(number) if sessions.list().iter().any(|s| s.number == number) => {
+                    selected = Some(number)
+                }
+                _ => println!("no session {}", arg),
+            },
+            "kill" => match arg.parse::<usize>() {
+                Ok(number) => match sessions.remove(number) {
+                    Some(session) => {
+                        let _ = send_remote_command(&session.mux, &Message::Close);
+                        println!("session {} killed", number);
+                        if selected == Some(number) {
+                            selected = None;
+                        }
+                    }
+                    None => println!("no session {}", number),
+                },
+                Err(_) => println!("usage: kill <n>"),
+            },
+            "exec" => {
+                let (command, timeout, compress) = match parse_exec(arg, timeout) {
+                    Some(exec) => exec,
+                    None => {
+                        println!("usage: exec [-t <seconds>] [-z|-Z] <command>");
+                        continue;
+                    }
+                };
+                with_session(sessions, selected, |session| {
+                    let compression = match compression(compress, session) {
+                        Some(compression) => compression,
+                        None => return,
+                    };
+                    let request = Message::Exec {
+                        command: command.to_string(),
+                        compression,
+                    };
+                    match send_remote_command(&session.mux, &request) {
+                        Ok(mut channel) => {
+                            // ctrl-c kills the command instead of the server
+                            let interrupts = kill_on_interrupt(&channel);
+                            handle_os_command(&mut channel, false, timeout, compression);
+                            terminal::release_interrupts();
+                            let _ = interrupts.join();
+                        }
+                        Err(e) => println!("error sending request: {}", e),
+                    }
+                })
+            }
+            "get" => {
+                let transfer = match parse_transfer(&args) {
+                    Some(transfer) => transfer,
+                    None => {
+                        println!(
+                            "usage: get [-r] [-i <glob>] [-x <glob>] [-z|-Z] <source> <target dir>"
+                        );
+                        continue;
+                    }
+                };
+                with_session(sessions, selected, |session| {
+                    let compression = match compression(transfer.compress, session) {
+                        Some(compression) => compression,
+                        None => return,
+                    };
+                    let path = transfer.source.to_string();
+                    let request = if transfer.recursive {
+                        Message::GetTree {
+                            path,
+                            include: transfer.include.clone(),
+                            exclude: transfer.exclude.clone(),
+                            compression,
+                        }
+                    } else {
+                        Message::Get { path, compression }
+                    };
+                    match send_remote_command(&session.mux, &request) {
+                        Ok(mut channel) if transfer.recursive => {
+                            handle_get_tree_command(&mut channel, transfer.target_dir, compression);
+                        }
+                        Ok(mut channel) => {
+                            handle_get_command(
+                                &mut channel,
+                                transfer.source,
+                                transfer.target_dir,
+                                compression,
+                            );
+                        }
+                        Err(e) => println!("error sending request: {}", e),
+                    }
+                })
+            }
+            "put" => {
+                let transfer = match parse_transfer(&args) {
+                    Some(transfer) => transfer,
+                    None => {
+                        println!(
+                            "usage: put [-r] [-i <glob>] [-x <glob>] [-z|-Z] <source> <target dir>"
+                        );
+                        continue;
+                    }
+                };
+                let name = match Path::new(transfer.source).file_name() {
+                    Some(name) => name.to_string_lossy().to_string(),
+                    None if transfer.recursive => String::new(),
+                    None => {
+                        println!("invalid source file {}", transfer.source);
+                        continue;
+                    }
+                };
+                with_session(sessions, selected, |session| {
+                    let compression = match compression(transfer.compress, session) {
+                        Some(compression) => compression,
+                        None => return,
+                    };
+                    let target_dir = transfer.target_dir.to_string();
+                    let request = if transfer.recursive {
+                        Message::PutTree {
+                            target_dir,
+                            compression,
+                        }
+                    } else {
+                        Message::Put {
+                            name,
+                            target_dir,
+                            compression,
+                        }
+                    };
+                    match send_remote_command(&session.mux, &request) {
+                        Ok(mut channel) if transfer.recursive => {
+                            handle_put_tree_command(
+                                &mut channel,
+                                transfer.source,
+                                &transfer.include,
+                                &transfer.exclude,
+                                compression,
+                            );
+                        }
+                        Ok(mut channel) => {
+                            handle_put_command(
+                                &mut channel,
+                                transfer.source,
+                                transfer.target_dir,
+                                compression,
+                            );
+                        }
+                        Err(e) => println!("error sending request: {}", e),
+                    }
+                })
+            }
+            "shell" => {
+                // -r is the default, kept for older habits
+                let mode = matches!(args.first(), Some(&"-c") | Some(&"-r"));
+                let cooked = args.first() == Some(&"-c");
+                let mut program = args
+                    .iter()
+                    .skip(if mode { 1 } else { 0 })
+                    .map(|arg| arg.to_string());
+                let request = shell_request(program.next(), program.collect(), vec![], None);
+                with_session(sessions, selected, |session| {
+                    match send_remote_command(&session.mux, &request) {
+                        Ok(channel) => {
+                            run_shell(channel, cooked);
+                        }
+                        Err(e) => println!("error sending request: {}", e),
+                    }
+                })
+            }
+            "quit" | "exit" => break,
+            _ => println!(
+                "unknown command {}, type help for the list of commands",
+                command
+            ),
+        }
+    }
+
+    if let Some(history) = &history {
+        let _ = editor.save_history(history);
+    }
+}
+
+/// runs f on the selected session, a session lost meanwhile is dropped
+fn with_session<F>(sessions: &Sessions, selected: Option<usize>, f: F)
+where
+    F: FnOnce(&Session),
+{
+    let number = match selected {
+        Some(number) => number,
+        None => {
+            println!("no session selected, use <n> first");
+            return;
+        }
+    };
+    let session = match sessions.get(number) {
+        Some(session) => session,
+        None => {
+            println!("no session {}", number);
+            return;
+        }
+    };
+    f(&session);
+    if session.mux.is_closed() {
+        sessions.remove(number);
+        println!("session {} closed", number);
+    }
+}
+
+/// sends Kill to the command on channel for every SIGINT until
+/// release_interrupts, like its timeout does
+fn kill_on_interrupt(channel: &Channel) -> JoinHandle<()> {
+    let interrupts = terminal::catch_interrupts();
+    let mut killer = channel.clone();
+    thread::spawn(move || {
+        for () in interrupts {
+            eprintln!("interrupted, killing the command");
+            if let Err(e) = write_message(&mut killer, &Message::Kill) {
+                eprintln!("error sending kill: {}", e);
+            }
+        }
+    })
+}
+
+/// compression of a request on session, prints why there is none
+fn compression(compress: Option<bool>, session: &Session) -> Option<Compression> {
+    match request_compression(compress, session.compression) {
+        Ok(compression) => Some(compression),
+        Err(e) => {
+            println!("{}", e);
+            None
+        }
+    }
+}
+
+/// splits the arguments of exec into the command, its timeout and -z or -Z,
+/// the options come before the command
+fn parse_exec(
+    arg: &str,
+    timeout: Option<Duration>,
+) -> Option<(&str, Option<Duration>, Option<bool>)> {
+    let mut command = arg;
+    let mut timeout = timeout;
+    let mut compress = None;
+    loop {
+        let (option, rest) = command.split_once(' ').unwrap_or((command, ""));
+        match option {
+            "-t" => {
+                let (seconds, rest) = rest.trim_start().split_once(' ')?;
+                timeout = Some(parse_timeout(seconds)?);
+                command = rest.trim_start();
+            }
+            "-z" => {
+                compress = Some(true);
+                command = rest.trim_start();
+            }
+            "-Z" => {
+                compress = Some(false);
+                command = rest.trim_start();
+            }
+            _ => break,
+        }
+    }
+    if command.is_empty() {
+        return None;
+    }
+    Some((command, timeout, compress))
+}
+
+/// arguments of get and put
+struct Transfer<'a> {
+    recursive: bool,
+    /// -z or -Z
+    compress: Option<bool>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    source: &'a str,
+    target_dir: &'a str,
+}
+
+/// parses [-r] [-i <glob>] [-x <glob>] [-z|-Z] <source> <target dir>, the
+/// globs need -r
+fn parse_transfer<'a>(args: &[&'a str]) -> Option<Transfer<'a>> {
+    let mut recursive = false;
+    let mut compress = None;
+    let mut include = vec![];
+    let mut exclude = vec![];
+    let mut paths = vec![];
+    let mut args = args.iter();
+    while let Some(&arg) = args.next() {
+        match arg {
+            "-r" => recursive = true,
+            "-z" => compress = Some(true),
+            "-Z" => compress = Some(false),
+            "-i" | "-x" => {
+                let glob = args.next()?;
+                if let Err(e) = globset::Glob::new(glob) {
+                    println!("invalid pattern: {}", e);
+                    return None;
+                }
+                if arg == "-i" {
+                    include.push(glob.to_string());
+                } else {
+                    exclude.push(glob.to_string());
+                }
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() != 2 || (!recursive && (!include.is_empty() || !exclude.is_empty())) {
+        return None;
+    }
+    Some(Transfer {
+        recursive,
+        compress,
+        include,
+        exclude,
+        source: paths[0],
+        target_dir: paths[1],
+    })
+}
+
+fn list_sessions(sessions: &Sessions) {
+    let list = sessions.list();
+    if list.is_empty() {
+        println!("no sessions");
+        return;
+    }
+    for session in list {
+        println!(
+            "{:>4}  {:<22} {}  {}",
+            session.number,
+            session.addr,
+            humantime::format_rfc3339_seconds(session.connected),
+            session.remote_id
+        );
+    }
+}
+
+fn history_file() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| Path::new(&home).join(".trsh_history"))
+}
@@ -0,0 +1,277 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! compression of file transfers and command output
+//!
+//! the algorithms are announced as capabilities in the handshake and a
+//! request carries the compression of its data. a compressed stream is the
+//! output of one encoder, flushed after every chunk so every Data message can
+//! be decompressed when it arrives. the data is compressed before the
+//! channel encrypts it, encrypted data does not compress.
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use flate2::write::{DeflateDecoder, DeflateEncoder};
+
+use crate::protocol::Compression;
+
+/// zstd level, favours speed like the zstd command line
+const ZSTD_LEVEL: i32 = 3;
+
+enum Encoder {
+    None,
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Deflate(DeflateEncoder<Vec<u8>>),
+}
+
+enum Decoder {
+    None,
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+    Deflate(DeflateDecoder<Vec<u8>>),
+}
+
+/// compresses the chunks of a stream and counts its bytes
+pub struct Compressor {
+    encoder: Encoder,
+    counter: Counter,
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> io::Result<Compressor> {
+        let encoder = match compression {
+            Compression::None => Encoder::None,
+            Compression::Zstd => {
+                Encoder::Zstd(zstd::stream::write::Encoder::new(vec![], ZSTD_LEVEL)?)
+            }
+            Compression::Deflate => {
+                Encoder::Deflate(DeflateEncoder::new(vec![], flate2::Compression::default()))
+            }
+        };
+        Ok(Compressor {
+            encoder,
+            counter: Counter::new(compression),
+        })
+    }
+
+    /// compresses data, the result decompresses to data on its own once
+    /// the previous chunks were decompressed
+    pub fn compress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let packed = match &mut self.encoder {
+            Encoder::None => data.to_vec(),
+            Encoder::Zstd(encoder) => flush_chunk(encoder, data, |e| e.get_mut())?,
+            Encoder::Deflate(encoder) => flush_chunk(encoder, data, |e| e.get_mut())?,
+        };
+        self.counter.count(data.len(), packed.len());
+        Ok(packed)
+    }
+
+    pub fn throughput(&self) -> Throughput {
+        self.counter.throughput()
+    }
+}
+
+/// decompresses the chunks of a stream sent by a Compressor and counts its
+/// bytes
+pub struct Decompressor {
+    decoder: Decoder,
+    counter: Counter,
+}
+
+impl Decompressor {
+    pub fn new(compression: Compression) -> io::Result<Decompressor> {
+        let decoder = match compression {
+            Compression::None => Decoder::None,
+            Compression::Zstd => Decoder::Zstd(zstd::stream::write::Decoder::new(vec![])?),
+            Compression::Deflate => Decoder::Deflate(DeflateDecoder::new(vec![])),
+        };
+        Ok(Decompressor {
+            decoder,
+            counter: Counter::new(compression),
+        })
+    }
+
+    pub fn decompress(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let unpacked = match &mut self.decoder {
+            Decoder::None => data.to_vec(),
+            Decoder::Zstd(decoder) => flush_chunk(decoder, data, |d| d.get_mut())?,
+            Decoder::Deflate(decoder) => flush_chunk(decoder, data, |d| d.get_mut())?,
+        };
+        self.counter.count(unpacked.len(), data.len());
+        Ok(unpacked)
+    }
+
+    pub fn throughput(&self) -> Throughput {
+        self.counter.throughput()
+    }
+}
+
+/// writes data through a coder and takes everything it wrote to its buffer
+fn flush_chunk<C, F>(coder: &mut C, data: &[u8], buffer: F) -> io::Result<Vec<u8>>
+where
+    C: Write,
+    F: Fn(&mut C) -> &mut Vec<u8>,
+{
+    coder.write_all(data)?;
+    coder.flush()?;
+    Ok(std::mem::take(buffer(coder)))
+}
+
+struct Counter {
+    compression: Compression,
+    started: Instant,
+    bytes: u64,
+    wire_bytes: u64,
+}
+
+impl Counter {
+    fn new(compression: Compression) -> Counter {
+        Counter {
+            compression,
+            started: Instant::now(),
+            bytes: 0,
+            wire_bytes: 0,
+        }
+    }
+
+    fn count(&mut self, bytes: usize, wire_bytes: usize) {
+        self.bytes += bytes as u64;
+        self.wire_bytes += wire_bytes as u64;
+    }
+
+    fn throughput(&self) -> Throughput {
+        Throughput {
+            compression: self.compression,
+            bytes: self.bytes,
+            wire_bytes: self.wire_bytes,
+            elapsed: self.started.elapsed(),
+        }
+    }
+}
+
+/// bytes of a stream before and after compression and the time it took
+#[derive(Debug, Clone, Default)]
+pub struct Throughput {
+    pub compression: Compression,
+    pub bytes: u64,
+    /// bytes in the Data messages
+    pub wire_bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    /// adds the bytes of another stream sent at the same time
+    pub fn add(&mut self, other: &Throughput) {
+        self.bytes += other.bytes;
+        self.wire_bytes += other.wire_bytes;
+        self.elapsed = self.elapsed.max(other.elapsed);
+    }
+
+    /// bytes per byte on the wire, 1 for an empty stream
+    pub fn ratio(&self) -> f64 {
+        if self.wire_bytes == 0 {
+            return 1.0;
+        }
+        self.bytes as f64 / self.wire_bytes as f64
+    }
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.compression != Compression::None {
+            write!(
+                f,
+                "{} compressed to {} bytes, ratio {:.2}, ",
+                self.compression,
+                self.wire_bytes,
+                self.ratio()
+            )?;
+        }
+        // a transfer quicker than a millisecond counts as one
+        let seconds = self.elapsed.as_secs_f64().max(0.001);
+        let rate = self.bytes as f64 / seconds;
+        if rate >= 1_000_000.0 {
+            write!(f, "{:.1} MB/s", rate / 1_000_000.0)
+        } else if rate >= 1_000.0 {
+            write!(f, "{:.1} kB/s", rate / 1_000.0)
+        } else {
+            write!(f, "{:.0} B/s", rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VARIANTS: [Compression; 3] = [Compression::None, Compression::Zstd, Compression::Deflate];
+
+    /// output of a command, repetitive text with a binary tail
+    fn chunks() -> Vec<Vec<u8>> {
+        let mut chunks = vec![];
+        for i in 0..20 {
+            chunks.push(
+                format!("line {} of the output\n", i)
+                    .repeat(100)
+                    .into_bytes(),
+            );
+        }
+        chunks.push(vec![]);
+        chunks.push((0..=255).collect());
+        chunks
+    }
+
+    #[test]
+    fn every_chunk_decompresses_on_arrival() {
+        for &compression in &VARIANTS {
+            let mut compressor = Compressor::new(compression).unwrap();
+            let mut decompressor = Decompressor::new(compression).unwrap();
+            let mut wire_bytes = 0;
+            for chunk in chunks() {
+                let packed = compressor.compress(&chunk).unwrap();
+                wire_bytes += packed.len() as u64;
+                assert_eq!(
+                    decompressor.decompress(&packed).unwrap(),
+                    chunk,
+                    "{}",
+                    compression
+                );
+            }
+            let bytes = chunks().iter().map(|c| c.len() as u64).sum::<u64>();
+            let sent = compressor.throughput();
+            let received = decompressor.throughput();
+            assert_eq!((sent.bytes, sent.wire_bytes), (bytes, wire_bytes));
+            assert_eq!((received.bytes, received.wire_bytes), (bytes, wire_bytes));
+            if compression != Compression::None {
+                assert!(wire_bytes < bytes / 4, "{} to {}", bytes, wire_bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn corrupt_stream_is_an_error() {
+        for &compression in &[Compression::Zstd, Compression::Deflate] {
+            let mut decompressor = Decompressor::new(compression).unwrap();
+            assert!(
+                decompressor.decompress(&[0xff; 64]).is_err(),
+                "{}",
+                compression
+            );
+
+            // a valid chunk followed by garbage
+            let mut compressor = Compressor::new(compression).unwrap();
+            let mut decompressor = Decompressor::new(compression).unwrap();
+            let packed = compressor.compress(b"a valid chunk").unwrap();
+            decompressor.decompress(&packed).unwrap();
+            assert!(
+                decompressor.decompress(&[0xff; 64]).is_err(),
+                "{}",
+                compression
+            );
+        }
+    }
+}
@@ -4,5 +4,14 @@
 // <LICENSE or http://opensource.org/licenses/MIT>, at your
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
+pub mod compress;
+pub mod cryptolib_aead;
 pub mod cryptolib_aes;
-pub mod cryptolib_salsa;
\ No newline at end of file
+pub mod cryptolib_handshake;
+pub mod cryptolib_salsa;
+pub mod digest;
+pub mod mux;
+pub mod protocol;
+pub mod transfer;
+pub mod tree;
+pub mod tty_modes;
@@ -0,0 +1,296 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! authenticated framed channel using XChaCha20-Poly1305
+//!
+//! every write is sealed into a record:
+//!
+//! <u32 length> <ciphertext + 16 byte tag>
+//!
+//! the nonce of a record is the random stream prefix sent by the
+//! sender followed by the sequence number of the record, the length
+//! header is authenticated as associated data. a record with an empty
+//! payload marks the end of the stream, so a truncated stream can be
+//! told apart from a finished one. tampered, reordered or truncated
+//! records are returned as io::Error.
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use rand::{thread_rng, RngCore};
+
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+
+/// size of the key in bytes
+pub const KEY_SIZE: usize = 32;
+/// size of the random per stream nonce prefix in bytes
+pub const PREFIX_SIZE: usize = 16;
+/// maximum payload of one record
+pub const MAX_RECORD: usize = 16*1024;
+
+const TAG_SIZE: usize = 16;
+
+pub struct AeadWriter<W: Write> {
+    inner: W,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_SIZE],
+    seq: u64,
+    finished: bool,
+}
+
+impl<W: Write> AeadWriter<W> {
+    pub fn new(key: &[u8], prefix: [u8; PREFIX_SIZE], inner: W) -> io::Result<Self> {
+        let cipher = XChaCha20Poly1305::new_from_slice(key).map_err(|_| invalid_key())?;
+        Ok(Self {
+            inner,
+            cipher,
+            prefix,
+            seq: 0,
+            finished: false,
+        })
+    }
+
+    /// creates the sending side of a stream with a fresh random nonce prefix,
+    /// the prefix is sent unencrypted to the peer before the first record
+    pub fn new_sender(key: &[u8], mut inner: W) -> io::Result<Self> {
+        let mut prefix = [0; PREFIX_SIZE];
+        thread_rng().fill_bytes(&mut prefix);
+        inner.write_all(&prefix)?;
+        Self::new(key, prefix, inner)
+    }
+
+    /// seals buf into one record and writes it to the inner writer
+    pub fn send_record(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::new(ErrorKind::BrokenPipe, "stream already finished"));
+        }
+        let header = ((buf.len() + TAG_SIZE) as u32).to_be_bytes();
+        let nonce = record_nonce(&self.prefix, self.seq);
+        let sealed = self
+            .cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: buf,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| io::Error::other("could not seal record"))?;
+        self.seq = next_seq(self.seq)?;
+        // one write per record, a separate header write stalls on nagle
+        let mut record = Vec::with_capacity(header.len() + sealed.len());
+        record.extend_from_slice(&header);
+        record.extend_from_slice(&sealed);
+        self.inner.write_all(&record)?;
+        self.inner.flush()
+    }
+
+    /// sends the end of stream record, further writes fail
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.send_record(&[])?;
+        self.finished = true;
+        Ok(())
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+}
+
+impl<W: Write> Write for AeadWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(MAX_RECORD);
+        self.send_record(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub struct AeadReader<R: Read> {
+    inner: R,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_SIZE],
+    seq: u64,
+    buffer: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl<R: Read> AeadReader<R> {
+    pub fn new(key: &[u8], prefix: [u8; PREFIX_SIZE], inner: R) -> io::Result<Self> {
+        let cipher = XChaCha20Poly1305::new_from_slice(key).map_err(|_| invalid_key())?;
+        Ok(Self {
+            inner,
+            cipher,
+            prefix,
+            seq: 0,
+            buffer: vec![],
+            pos: 0,
+            finished: false,
+        })
+    }
+
+    /// creates the receiving side of a stream, reading the nonce
+    /// prefix sent by the peers new_sender
+    pub fn new_receiver(key: &[u8], mut inner: R) -> io::Result<Self> {
+        let mut prefix = [0; PREFIX_SIZE];
+        inner.read_exact(&mut prefix)?;
+        Self::new(key, prefix, inner)
+    }
+
+    /// reads and opens the next record, returns None at the end of the stream
+    pub fn recv_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.finished {
+            return Ok(None);
+        }
+        let mut header = [0; 4];
+        if let Err(e) = self.inner.read_exact(&mut header) {
+            if e.kind() == ErrorKind::UnexpectedEof {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "stream truncated"));
+            }
+            return Err(e);
+        }
+        let len = u32::from_be_bytes(header) as usize;
+        if !(TAG_SIZE..=MAX_RECORD + TAG_SIZE).contains(&len) {
+            return Err(io::Error::new(ErrorKind::InvalidData, "invalid record length"));
+        }
+        let mut sealed = vec![0; len];
+        self.inner.read_exact(&mut sealed)?;
+
+        let nonce = record_nonce(&self.prefix, self.seq);
+        let plain = self
+            .cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &sealed,
+                    aad: &header,
+                },
+            )
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "record authentication failed"))?;
+        self.seq = next_seq(self.seq)?;
+
+        if plain.is_empty() {
+            self.finished = true;
+            return Ok(None);
+        }
+        Ok(Some(plain))
+    }
+
+    /// true once the end of stream record was read
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R: Read> Read for AeadReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buffer.len() {
+            match self.recv_record()? {
+                Some(record) => {
+                    self.buffer = record;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.buffer.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buffer[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+fn record_nonce(prefix: &[u8; PREFIX_SIZE], seq: u64) -> [u8; 24] {
+    let mut nonce = [0; 24];
+    nonce[..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..].copy_from_slice(&seq.to_be_bytes());
+    nonce
+}
+
+fn next_seq(seq: u64) -> io::Result<u64> {
+    seq.checked_add(1)
+        .ok_or_else(|| io::Error::other("record sequence number exhausted"))
+}
+
+fn invalid_key() -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, "invalid key length")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; KEY_SIZE] = [7; KEY_SIZE];
+    const PREFIX: [u8; PREFIX_SIZE] = [3; PREFIX_SIZE];
+
+    fn seal(records: &[&[u8]], finish: bool) -> Vec<u8> {
+        let mut writer = AeadWriter::new(&KEY, PREFIX, vec![]).unwrap();
+        for record in records {
+            writer.send_record(record).unwrap();
+        }
+        if finish {
+            writer.finish().unwrap();
+        }
+        writer.inner
+    }
+
+    fn reader(wire: Vec<u8>) -> AeadReader<io::Cursor<Vec<u8>>> {
+        AeadReader::new(&KEY, PREFIX, io::Cursor::new(wire)).unwrap()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut reader = reader(seal(&[b"first", b"second"], true));
+        assert_eq!(reader.recv_record().unwrap().unwrap(), b"first");
+        assert_eq!(reader.recv_record().unwrap().unwrap(), b"second");
+        assert!(reader.recv_record().unwrap().is_none());
+        assert!(reader.is_finished());
+    }
+
+    #[test]
+    fn flipped_byte_is_rejected() {
+        let mut wire = seal(&[b"payload"], true);
+        wire[4 + 2] ^= 0x01;
+        let err = reader(wire).recv_record().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn swapped_records_are_rejected() {
+        let wire = seal(&[b"record a", b"record b"], true);
+        // both records have the same length, header and sealed payload
+        let len = 4 + b"record a".len() + TAG_SIZE;
+        let mut swapped = wire[len..2 * len].to_vec();
+        swapped.extend_from_slice(&wire[..len]);
+        swapped.extend_from_slice(&wire[2 * len..]);
+        let err = reader(swapped).recv_record().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_stream_is_not_finished() {
+        let mut reader = reader(seal(&[b"payload"], false));
+        assert_eq!(reader.recv_record().unwrap().unwrap(), b"payload");
+        let err = reader.recv_record().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(!reader.is_finished());
+    }
+}
@@ -0,0 +1,513 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! ephemeral x25519 key exchange with mutual challenge-response
+//! authentication, run at the start of every connection
+//!
+//! client                                server
+//!   version header,
+//!   ephemeral public key, challenge -->
+//!                                   <-- version header,
+//!                                       ephemeral public key, challenge,
+//!                                       proof over the client challenge
+//!   proof over the server challenge -->
+//!
+//! the version header is "TRSH", the u16 protocol version and the u32
+//! capability bitmap of the sender. on a version mismatch the server only
+//! answers with its version header and closes the connection, so both sides
+//! can report which versions were involved.
+//!
+//! a proof is a HMAC-SHA256 over the peers fresh challenge, both version
+//! headers and both public keys, keyed with a key derived from the static
+//! build key. each side proves that it knows the static key, and as the
+//! challenges are random per connection a recorded proof is useless in
+//! another session.
+//!
+//! the session keys are derived from the shared secret with HKDF-SHA256,
+//! salted with the static build key, one key per direction. the ephemeral
+//! secrets only live for the handshake, so a leaked static key does not
+//! decrypt recorded sessions.
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use rand::{thread_rng, RngCore};
+
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use crate::cryptolib_aead::{AeadReader, AeadWriter, KEY_SIZE};
+use crate::protocol::{CAPABILITIES, CAP_XCHACHA20_POLY1305, PROTOCOL_VERSION};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAGIC: &[u8; 4] = b"TRSH";
+const HEADER_SIZE: usize = 10;
+const PUBLIC_KEY_SIZE: usize = 32;
+const CHALLENGE_SIZE: usize = 32;
+const PROOF_SIZE: usize = 32;
+
+const INFO_CLIENT_TO_SERVER: &[u8] = b"trsh client to server";
+const INFO_SERVER_TO_CLIENT: &[u8] = b"trsh server to client";
+const INFO_AUTH: &[u8] = b"trsh challenge response";
+const LABEL_CLIENT_PROOF: &[u8] = b"trsh client proof";
+const LABEL_SERVER_PROOF: &[u8] = b"trsh server proof";
+
+/// keys of one session, send encrypts the own direction, recv the peers.
+/// caps are the capabilities announced by both sides
+pub struct SessionKeys {
+    pub send: [u8; KEY_SIZE],
+    pub recv: [u8; KEY_SIZE],
+    pub caps: u32,
+}
+
+impl SessionKeys {
+    /// opens the encrypted channel of the session on stream, both sides
+    /// have to open it right after the handshake
+    pub fn open_channel(
+        &self,
+        stream: &TcpStream,
+    ) -> io::Result<(AeadWriter<TcpStream>, AeadReader<TcpStream>)> {
+        let writer = AeadWriter::new_sender(&self.send, stream.try_clone()?)?;
+        let reader = AeadReader::new_receiver(&self.recv, stream.try_clone()?)?;
+        Ok((writer, reader))
+    }
+}
+
+/// runs the client side of the handshake, psk is the static build key
+pub fn client_handshake<S>(stream: &mut S, psk: &[u8]) -> io::Result<SessionKeys>
+where
+    S: Read + Write + ?Sized,
+{
+    let secret = EphemeralSecret::random_from_rng(thread_rng());
+    let public = PublicKey::from(&secret);
+    let challenge = new_challenge();
+    let header = version_header();
+
+    stream.write_all(&header)?;
+    stream.write_all(public.as_bytes())?;
+    stream.write_all(&challenge)?;
+    stream.flush()?;
+
+    let peer_header = read_version_header(stream)?;
+    let caps = negotiate(&peer_header, "server")?;
+    let peer = read_public_key(stream)?;
+    let mut peer_challenge = [0; CHALLENGE_SIZE];
+    stream.read_exact(&mut peer_challenge)?;
+    let mut peer_proof = [0; PROOF_SIZE];
+    stream.read_exact(&mut peer_proof)?;
+
+    let auth = auth_key(psk)?;
+    let transcript = Transcript {
+        client_header: &header,
+        server_header: &peer_header,
+        client: &public,
+        server: &peer,
+    };
+    // the proof goes out before the one of the server is checked, so a
+    // server with another key fails on it instead of a closed connection
+    let proof = prove(&auth, LABEL_CLIENT_PROOF, &peer_challenge, &transcript);
+    stream.write_all(&proof)?;
+    stream.flush()?;
+    verify(
+        &auth,
+        LABEL_SERVER_PROOF,
+        &challenge,
+        &transcript,
+        &peer_proof,
+    )?;
+
+    let (c2s, s2c) = derive_keys(secret, &peer, &public, &peer, psk)?;
+    Ok(SessionKeys {
+        send: c2s,
+        recv: s2c,
+        caps,
+    })
+}
+
+/// runs the server side of the handshake, psk is the static build key
+pub fn server_handshake<S>(stream: &mut S, psk: &[u8]) -> io::Result<SessionKeys>
+where
+    S: Read + Write + ?Sized,
+{
+    let secret = EphemeralSecret::random_from_rng(thread_rng());
+    let public = PublicKey::from(&secret);
+    let challenge = new_challenge();
+    let header = version_header();
+
+    let peer_header = read_version_header(stream)?;
+    let caps = match negotiate(&peer_header, "client") {
+        Ok(caps) => caps,
+        Err(e) => {
+            // let the client know which version we speak before hanging up
+            let _ = stream.write_all(&header);
+            let _ = stream.flush();
+            return Err(e);
+        }
+    };
+    let peer = read_public_key(stream)?;
+    let mut peer_challenge = [0; CHALLENGE_SIZE];
+    stream.read_exact(&mut peer_challenge)?;
+
+    let auth = auth_key(psk)?;
+    let transcript = Transcript {
+        client_header: &peer_header,
+        server_header: &header,
+        client: &peer,
+        server: &public,
+    };
+    let proof = prove(&auth, LABEL_SERVER_PROOF, &peer_challenge, &transcript);
+    stream.write_all(&header)?;
+    stream.write_all(public.as_bytes())?;
+    stream.write_all(&challenge)?;
+    stream.write_all(&proof)?;
+    stream.flush()?;
+
+    let mut peer_proof = [0; PROOF_SIZE];
+    stream.read_exact(&mut peer_proof)?;
+    verify(
+        &auth,
+        LABEL_CLIENT_PROOF,
+        &challenge,
+        &transcript,
+        &peer_proof,
+    )?;
+
+    let (c2s, s2c) = derive_keys(secret, &peer, &peer, &public, psk)?;
+    Ok(SessionKeys {
+        send: s2c,
+        recv: c2s,
+        caps,
+    })
+}
+
+/// the data both proofs are bound to
+struct Transcript<'a> {
+    client_header: &'a [u8; HEADER_SIZE],
+    server_header: &'a [u8; HEADER_SIZE],
+    client: &'a PublicKey,
+    server: &'a PublicKey,
+}
+
+fn version_header() -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[..4].copy_from_slice(MAGIC);
+    header[4..6].copy_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+    header[6..].copy_from_slice(&CAPABILITIES.to_be_bytes());
+    header
+}
+
+fn read_version_header<S: Read + ?Sized>(stream: &mut S) -> io::Result<[u8; HEADER_SIZE]> {
+    let mut header = [0; HEADER_SIZE];
+    stream.read_exact(&mut header)?;
+    Ok(header)
+}
+
+/// checks the peers version header and returns the common capabilities
+fn negotiate(header: &[u8; HEADER_SIZE], peer: &str) -> io::Result<u32> {
+    if &header[..4] != MAGIC {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{} does not speak the trsh protocol", peer),
+        ));
+    }
+    let version = u16::from_be_bytes([header[4], header[5]]);
+    if version != PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "protocol version mismatch, {} speaks version {}, we speak version {}",
+                peer, version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+    let peer_caps = u32::from_be_bytes([header[6], header[7], header[8], header[9]]);
+    let caps = peer_caps & CAPABILITIES;
+    if caps & CAP_XCHACHA20_POLY1305 == 0 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "no common cipher with {} (capabilities {:#x})",
+                peer, peer_caps
+            ),
+        ));
+    }
+    Ok(caps)
+}
+
+fn read_public_key<S: Read + ?Sized>(stream: &mut S) -> io::Result<PublicKey> {
+    let mut peer = [0; PUBLIC_KEY_SIZE];
+    stream.read_exact(&mut peer)?;
+    Ok(PublicKey::from(peer))
+}
+
+fn new_challenge() -> [u8; CHALLENGE_SIZE] {
+    let mut challenge = [0; CHALLENGE_SIZE];
+    thread_rng().fill_bytes(&mut challenge);
+    challenge
+}
+
+/// derives the key for the challenge-response proofs from the static key
+fn auth_key(psk: &[u8]) -> io::Result<[u8; KEY_SIZE]> {
+    let mut key = [0; KEY_SIZE];
+    Hkdf::<Sha256>::new(None, psk)
+        .expand(INFO_AUTH, &mut key)
+        .map_err(|_| io::Error::other("could not derive authentication key"))?;
+    Ok(key)
+}
+
+fn proof_mac(auth: &[u8], label: &[u8], challenge: &[u8], transcript: &Transcript) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(auth).expect("hmac accepts any key length");
+    mac.update(label);
+    mac.update(challenge);
+    mac.update(transcript.client_header);
+    mac.update(transcript.server_header);
+    mac.update(transcript.client.as_bytes());
+    mac.update(transcript.server.as_bytes());
+    mac
+}
+
+fn prove(auth: &[u8], label: &[u8], challenge: &[u8], transcript: &Transcript) -> [u8; PROOF_SIZE] {
+    proof_mac(auth, label, challenge, transcript)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+fn verify(
+    auth: &[u8],
+    label: &[u8],
+    challenge: &[u8],
+    transcript: &Transcript,
+    proof: &[u8],
+) -> io::Result<()> {
+    proof_mac(auth, label, challenge, transcript)
+        .verify_slice(proof)
+        .map_err(|_| io::Error::new(ErrorKind::PermissionDenied, "peer authentication failed"))
+}
+
+/// returns the client to server and the server to client key
+fn derive_keys(
+    secret: EphemeralSecret,
+    peer: &PublicKey,
+    client: &PublicKey,
+    server: &PublicKey,
+    psk: &[u8],
+) -> io::Result<([u8; KEY_SIZE], [u8; KEY_SIZE])> {
+    let shared = secret.diffie_hellman(peer);
+    if !shared.was_contributory() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid peer public key",
+        ));
+    }
+
+    let hk = Hkdf::<Sha256>::new(Some(psk), shared.as_bytes());
+    let mut c2s = [0; KEY_SIZE];
+    let mut s2c = [0; KEY_SIZE];
+    expand(&hk, INFO_CLIENT_TO_SERVER, client, server, &mut c2s)?;
+    expand(&hk, INFO_SERVER_TO_CLIENT, client, server, &mut s2c)?;
+    Ok((c2s, s2c))
+}
+
+fn expand(
+    hk: &Hkdf<Sha256>,
+    label: &[u8],
+    client: &PublicKey,
+    server: &PublicKey,
+    okm: &mut [u8],
+) -> io::Result<()> {
+    hk.expand_multi_info(&[label, client.as_bytes(), server.as_bytes()], okm)
+        .map_err(|_| io::Error::other("could not derive session key"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::CAP_DEFLATE;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    const PSK: &[u8] = b"Fahm9Oruet8zahcoFahm9Oruet8zahco";
+
+    /// a stream that records what is written to it and flips the byte at
+    /// flip on its way
+    struct Tap {
+        inner: UnixStream,
+        written: Vec<u8>,
+        flip: Option<usize>,
+    }
+
+    impl Tap {
+        fn new(inner: UnixStream, flip: Option<usize>) -> Self {
+            Tap {
+                inner,
+                written: vec![],
+                flip,
+            }
+        }
+    }
+
+    impl Read for Tap {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for Tap {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut data = buf.to_vec();
+            if let Some(pos) = self.flip {
+                if (self.written.len()..self.written.len() + data.len()).contains(&pos) {
+                    data[pos - self.written.len()] ^= 0x01;
+                }
+            }
+            self.inner.write_all(&data)?;
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// offset of the proof in the flight of the server and in the second
+    /// flight of the client
+    const PROOF_OFFSET: usize = HEADER_SIZE + PUBLIC_KEY_SIZE + CHALLENGE_SIZE;
+
+    fn run(
+        client_psk: &'static [u8],
+        server_psk: &'static [u8],
+        client_flip: Option<usize>,
+        server_flip: Option<usize>,
+    ) -> (io::Result<SessionKeys>, io::Result<SessionKeys>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let mut server = Tap::new(server, server_flip);
+            server_handshake(&mut server, server_psk)
+        });
+        let mut client = Tap::new(client, client_flip);
+        let client = client_handshake(&mut client, client_psk);
+        (client, server.join().unwrap())
+    }
+
+    fn assert_denied(result: io::Result<SessionKeys>) {
+        match result {
+            Ok(_) => panic!("handshake succeeded"),
+            Err(e) => assert_eq!(e.kind(), ErrorKind::PermissionDenied, "{}", e),
+        }
+    }
+
+    #[test]
+    fn handshake_derives_matching_keys() {
+        let (client, server) = run(PSK, PSK, None, None);
+        let (client, server) = (client.unwrap(), server.unwrap());
+        assert_eq!(client.send, server.recv);
+        assert_eq!(client.recv, server.send);
+        assert_ne!(client.send, client.recv);
+        assert_eq!(client.caps, CAPABILITIES);
+        assert_eq!(server.caps, CAPABILITIES);
+    }
+
+    #[test]
+    fn wrong_psk_is_denied_on_both_sides() {
+        let (client, server) = run(PSK, b"another key", None, None);
+        assert_denied(client);
+        assert_denied(server);
+    }
+
+    #[test]
+    fn flipped_proof_byte_is_rejected() {
+        let (client, _) = run(PSK, PSK, None, Some(PROOF_OFFSET + 5));
+        assert_denied(client);
+        let (_, server) = run(PSK, PSK, Some(PROOF_OFFSET + 31), None);
+        assert_denied(server);
+    }
+
+    #[test]
+    fn replayed_proof_is_rejected() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let recorded = thread::spawn(move || {
+            let mut server = server;
+            server_handshake(&mut server, PSK).unwrap();
+        });
+        let mut client = Tap::new(client, None);
+        client_handshake(&mut client, PSK).unwrap();
+        recorded.join().unwrap();
+        let flight = client.written;
+        assert_eq!(flight.len(), PROOF_OFFSET + PROOF_SIZE);
+
+        // the recorded client flight against a fresh server challenge
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let replayed = thread::spawn(move || {
+            let mut server = server;
+            server_handshake(&mut server, PSK)
+        });
+        client.write_all(&flight[..PROOF_OFFSET]).unwrap();
+        let mut reply = [0; PROOF_OFFSET + PROOF_SIZE];
+        client.read_exact(&mut reply).unwrap();
+        client.write_all(&flight[PROOF_OFFSET..]).unwrap();
+        assert_denied(replayed.join().unwrap());
+    }
+
+    fn header(magic: &[u8; 4], version: u16, caps: u32) -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(magic);
+        header[4..6].copy_from_slice(&version.to_be_bytes());
+        header[6..].copy_from_slice(&caps.to_be_bytes());
+        header
+    }
+
+    fn negotiate_error(header: [u8; HEADER_SIZE]) -> io::Error {
+        let err = negotiate(&header, "server").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        err
+    }
+
+    #[test]
+    fn common_capabilities_are_negotiated() {
+        let caps = negotiate(&header(MAGIC, PROTOCOL_VERSION, u32::MAX), "server").unwrap();
+        assert_eq!(caps, CAPABILITIES);
+        let caps = CAP_XCHACHA20_POLY1305 | CAP_DEFLATE;
+        assert_eq!(
+            negotiate(&header(MAGIC, PROTOCOL_VERSION, caps), "server").unwrap(),
+            caps
+        );
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let err = negotiate_error(header(b"SSH-", PROTOCOL_VERSION, CAPABILITIES));
+        assert_eq!(err.to_string(), "server does not speak the trsh protocol");
+    }
+
+    #[test]
+    fn other_version_is_rejected() {
+        let err = negotiate_error(header(MAGIC, PROTOCOL_VERSION + 1, CAPABILITIES));
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "protocol version mismatch, server speaks version {}, we speak version {}",
+                PROTOCOL_VERSION + 1,
+                PROTOCOL_VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn missing_cipher_is_rejected() {
+        let caps = CAPABILITIES & !CAP_XCHACHA20_POLY1305;
+        let err = negotiate_error(header(MAGIC, PROTOCOL_VERSION, caps));
+        assert_eq!(
+            err.to_string(),
+            format!("no common cipher with server (capabilities {:#x})", caps)
+        );
+    }
+}
@@ -8,6 +8,8 @@ use salsa20::cipher::stream::InvalidKeyNonceLength;
 use salsa20::cipher::{NewStreamCipher, SyncStreamCipher};
 use salsa20::Salsa20;
 
+use rand::{thread_rng, RngCore};
+
 use std::io;
 use std::{
     io::{ErrorKind, Read, Write},
@@ -17,6 +19,9 @@ use std::{
 const DEFAULT_BUF_SIZE: usize = 1;
 const BUF_SIZE: usize = 8*1024;
 
+/// size of the salsa20 nonce in bytes
+pub const NONCE_SIZE: usize = 8;
+
 pub struct Crypto {
     cipher: Salsa20,
     buffer: Vec<u8>,
@@ -24,29 +29,42 @@ pub struct Crypto {
 
 impl Crypto {
     pub fn new(key: &[u8], iv: &[u8]) -> Result<Self, InvalidKeyNonceLength> {
-        let skey = salsa20::Key::from_slice(key);
-        let nonce = salsa20::Nonce::from_slice(iv);
-        let cipher = Salsa20::new(skey, nonce);
+        let cipher = Salsa20::new_var(key, iv)?;
         let buffer = vec![];
-        Ok(Self {
-            cipher: cipher,
-            buffer: buffer,
-        })
+        Ok(Self { cipher, buffer })
+    }
+
+    /// creates the encrypting side of a stream with a fresh random nonce,
+    /// the nonce is sent unencrypted to the peer before any ciphertext
+    pub fn new_sender<W: Write + ?Sized>(key: &[u8], writer: &mut W) -> io::Result<Self> {
+        let nonce = generate_nonce();
+        let cr = Self::new(key, &nonce).map_err(invalid_key)?;
+        writer.write_all(&nonce)?;
+        writer.flush()?;
+        Ok(cr)
+    }
+
+    /// creates the decrypting side of a stream, reading the nonce
+    /// sent by the peers new_sender
+    pub fn new_receiver<R: Read + ?Sized>(key: &[u8], reader: &mut R) -> io::Result<Self> {
+        let mut nonce = [0; NONCE_SIZE];
+        reader.read_exact(&mut nonce)?;
+        Self::new(key, &nonce).map_err(invalid_key)
     }
 
     pub fn buffer(&self) -> &[u8] {
         &self.buffer
     }
 
-    pub fn copy_buf<R: ?Sized, W: ?Sized>(
+    pub fn copy_buf<R, W>(
         &mut self,
         reader: &mut R,
         writer: &mut W,
         tx: &mpsc::Sender<u64>,
     ) -> io::Result<u64>
     where
-        R: Read,
-        W: Write,
+        R: Read + ?Sized,
+        W: Write + ?Sized,
     {
         let mut buffer: [u8; BUF_SIZE] = [0; BUF_SIZE];
 
@@ -59,16 +77,16 @@ impl Crypto {
                 Err(e) => return Err(e),
             };
             self.cipher.apply_keystream(&mut buffer[..len]);
-            writer.write_all(&mut buffer[..len])?;
+            writer.write_all(&buffer[..len])?;
             written += len as u64;
             tx.send(written).unwrap();
         }
     }
 
-    pub fn copy<R: ?Sized, W: ?Sized>(&mut self, reader: &mut R, writer: &mut W) -> io::Result<u64>
+    pub fn copy<R, W>(&mut self, reader: &mut R, writer: &mut W) -> io::Result<u64>
     where
-        R: Read,
-        W: Write,
+        R: Read + ?Sized,
+        W: Write + ?Sized,
     {
         let mut buffer: [u8; DEFAULT_BUF_SIZE] = [0; DEFAULT_BUF_SIZE];
 
@@ -81,11 +99,23 @@ impl Crypto {
                 Err(e) => return Err(e),
             };
             self.cipher.apply_keystream(&mut buffer[..len]);
-            writer.write_all(&mut buffer[..len])?;
+            writer.write_all(&buffer[..len])?;
             written += len as u64;
         }
     }
 }
+
+/// returns a fresh random nonce, a nonce must never be used twice with the same key
+pub fn generate_nonce() -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+fn invalid_key(e: InvalidKeyNonceLength) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, e.to_string())
+}
+
 impl Read for Crypto {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.buffer = buf.to_vec();
@@ -102,6 +132,7 @@ impl Write for Crypto {
     }
 
     fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
-        unimplemented!()
+        // write leaves its result in buffer, there is nothing to flush
+        Ok(())
     }
 }
@@ -0,0 +1,91 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! SHA-256 digests of transferred data
+//!
+//! HashReader and HashWriter hash the bytes passing through them, so the
+//! sender hashes a file while it streams it and the receiver while it
+//! writes it.
+use std::io::{self, Read, Write};
+
+use sha2::{Digest, Sha256};
+
+/// length of a digest
+pub const DIGEST_LEN: usize = 32;
+
+/// hashes everything read from the inner reader
+pub struct HashReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashReader<R> {
+    pub fn new(inner: R) -> HashReader<R> {
+        HashReader {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// digest of the bytes read so far
+    pub fn digest(&self) -> [u8; DIGEST_LEN] {
+        self.hasher.clone().finalize().into()
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// hashes everything written to the inner writer
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashWriter<W> {
+    pub fn new(inner: W) -> HashWriter<W> {
+        HashWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// continues the digest of prefix, for appending to the data it read
+    pub fn after<R>(inner: W, prefix: HashReader<R>) -> HashWriter<W> {
+        HashWriter {
+            inner,
+            hasher: prefix.hasher,
+        }
+    }
+
+    /// digest of the bytes written so far
+    pub fn digest(&self) -> [u8; DIGEST_LEN] {
+        self.hasher.clone().finalize().into()
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// lower case hex of a digest, as printed by sha256sum
+pub fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,742 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! multiplexes independent channels over one encrypted connection
+//!
+//! every frame is
+//!
+//! <u32 length> <u8 type> <u32 channel id> <payload>
+//!
+//! a channel is opened with Open carrying the receive window of the
+//! opener and confirmed with Confirm carrying the window of the peer.
+//! like in ssh a side may only send as many Data bytes as the window of
+//! the peer allows, the receiver grows the window with Window once the
+//! data is consumed, so a slow channel never blocks the others. Close
+//! ends the channel, the end of the data of one direction is left to the
+//! messages on the channel.
+//!
+//! channel ids are chosen by the opener, the client uses odd and the
+//! server even ids. a background thread reads the connection and
+//! dispatches the frames, channels opened by the peer are returned by
+//! accept. the frames are queued for a writer thread, so the reader never
+//! waits for a full connection while the peer waits for us to read. dropping
+//! the mux finishes the encrypted stream, so the peer can tell a closed
+//! connection from a lost one.
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::cryptolib_aead::{AeadReader, AeadWriter};
+
+/// receive window of a new channel
+pub const INITIAL_WINDOW: u32 = 256*1024;
+/// largest payload of one Data frame
+pub const MAX_PACKET: usize = 32*1024;
+
+const FRAME_HEADER: usize = 5;
+/// time dropping the mux waits for the queued frames to be written
+const LINGER: Duration = Duration::from_secs(5);
+
+const OPEN: u8 = 1;
+const CONFIRM: u8 = 2;
+const DATA: u8 = 3;
+const WINDOW: u8 = 4;
+const CLOSE: u8 = 6;
+
+pub struct Mux {
+    shared: Arc<Shared>,
+    incoming: Mutex<mpsc::Receiver<Channel>>,
+    /// disconnected when the writer thread ends
+    written: Mutex<mpsc::Receiver<()>>,
+}
+
+struct Shared {
+    outgoing: Mutex<mpsc::Sender<Outgoing>>,
+    table: Mutex<Table>,
+    stream: TcpStream,
+}
+
+/// work of the writer thread
+enum Outgoing {
+    Frame(Vec<u8>),
+    /// finishes the encrypted stream and ends the writer thread
+    Finish,
+}
+
+struct Table {
+    channels: HashMap<u32, Arc<Slot>>,
+    next_id: u32,
+    closed: bool,
+}
+
+/// state of one channel, shared by the handles and the reader thread
+struct Slot {
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    confirmed: bool,
+    buffer: VecDeque<u8>,
+    /// bytes the peer may still send
+    local_window: u32,
+    /// bytes consumed since the last Window frame
+    consumed: u32,
+    /// bytes we may still send
+    remote_window: u32,
+    close_sent: bool,
+    close_received: bool,
+    /// the connection is gone
+    dead: bool,
+}
+
+impl Mux {
+    /// starts the mux on the client side of a connection
+    pub fn client(
+        writer: AeadWriter<TcpStream>,
+        reader: AeadReader<TcpStream>,
+    ) -> io::Result<Self> {
+        Self::new(writer, reader, 1)
+    }
+
+    /// starts the mux on the server side of a connection
+    pub fn server(
+        writer: AeadWriter<TcpStream>,
+        reader: AeadReader<TcpStream>,
+    ) -> io::Result<Self> {
+        Self::new(writer, reader, 2)
+    }
+
+    fn new(
+        writer: AeadWriter<TcpStream>,
+        reader: AeadReader<TcpStream>,
+        first_id: u32,
+    ) -> io::Result<Self> {
+        let stream = writer.get_ref().try_clone()?;
+        let (outgoing, queue) = mpsc::channel();
+        let shared = Arc::new(Shared {
+            outgoing: Mutex::new(outgoing),
+            table: Mutex::new(Table {
+                channels: HashMap::new(),
+                next_id: first_id,
+                closed: false,
+            }),
+            stream,
+        });
+        let (done, written) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let _done = done;
+            let mut writer = writer;
+            // a failed write is a lost connection, the reader thread sees it
+            // too, reports it and wakes up the channels
+            if write_frames(&mut writer, queue).is_err() {
+                let _ = writer.get_ref().shutdown(Shutdown::Both);
+            }
+        });
+        let (tx, rx) = mpsc::channel();
+        let reader_shared = shared.clone();
+        thread::spawn(move || {
+            let mut reader = reader;
+            if let Err(e) = reader_shared.dispatch(&mut reader, &tx) {
+                // a peer ending the connection finishes the stream first,
+                // a closed table is our own end
+                let closed = reader_shared.table.lock().unwrap().closed;
+                if !reader.is_finished() && !closed {
+                    println!("mux: {}", e);
+                }
+            }
+            reader_shared.shutdown();
+        });
+        Ok(Self {
+            shared,
+            incoming: Mutex::new(rx),
+            written: Mutex::new(written),
+        })
+    }
+
+    /// opens a new channel and waits for the peer to confirm it
+    pub fn open(&self) -> io::Result<Channel> {
+        let slot = Arc::new(Slot::new(false, 0));
+        let id = {
+            let mut table = self.shared.table.lock().unwrap();
+            if table.closed {
+                return Err(connection_lost());
+            }
+            let id = table.next_id;
+            table.next_id = table.next_id.wrapping_add(2);
+            table.channels.insert(id, slot.clone());
+            id
+        };
+        self.shared
+            .send_frame(OPEN, id, &INITIAL_WINDOW.to_be_bytes())?;
+
+        let mut state = slot.state.lock().unwrap();
+        while !state.confirmed && !state.dead && !state.close_received {
+            state = slot.cond.wait(state).unwrap();
+        }
+        if !state.confirmed {
+            return Err(connection_lost());
+        }
+        drop(state);
+        Ok(Channel::new(id, slot, self.shared.clone()))
+    }
+
+    /// waits for the next channel opened by the peer
+    pub fn accept(&self) -> io::Result<Channel> {
+        self.incoming
+            .lock()
+            .unwrap()
+            .recv()
+            .map_err(|_| connection_lost())
+    }
+
+    /// true once the connection is gone
+    pub fn is_closed(&self) -> bool {
+        self.shared.table.lock().unwrap().closed
+    }
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        // the peer sees the queued frames and a finished stream instead of
+        // a truncated one, a writer blocked on a full connection is not
+        // waited for longer than LINGER
+        self.shared.table.lock().unwrap().closed = true;
+        let _ = self.shared.queue(Outgoing::Finish);
+        let _ = self.written.get_mut().unwrap().recv_timeout(LINGER);
+        // ends the reader thread, the open channels see a lost connection
+        let _ = self.shared.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl Shared {
+    /// queues a frame for the writer thread, it never blocks
+    fn send_frame(&self, kind: u8, id: u32, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(4 + FRAME_HEADER + payload.len());
+        frame.extend_from_slice(&((FRAME_HEADER + payload.len()) as u32).to_be_bytes());
+        frame.push(kind);
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        self.queue(Outgoing::Frame(frame))
+    }
+
+    fn queue(&self, outgoing: Outgoing) -> io::Result<()> {
+        self.outgoing
+            .lock()
+            .unwrap()
+            .send(outgoing)
+            .map_err(|_| connection_lost())
+    }
+
+    /// reads the frames of the peer until the connection ends
+    fn dispatch(
+        self: &Arc<Self>,
+        reader: &mut AeadReader<TcpStream>,
+        incoming: &mpsc::Sender<Channel>,
+    ) -> io::Result<()> {
+        loop {
+            let mut header = [0; 4];
+            reader.read_exact(&mut header)?;
+            let len = u32::from_be_bytes(header) as usize;
+            if !(FRAME_HEADER..=FRAME_HEADER + MAX_PACKET).contains(&len) {
+                return Err(invalid("invalid frame length"));
+            }
+            let mut frame = vec![0; len];
+            reader.read_exact(&mut frame)?;
+            let kind = frame[0];
+            let id = u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]);
+            let payload = &frame[FRAME_HEADER..];
+
+            if kind == OPEN {
+                let window = read_u32(payload)?;
+                let slot = Arc::new(Slot::new(true, window));
+                let mut table = self.table.lock().unwrap();
+                if table.channels.contains_key(&id) {
+                    return Err(invalid("channel id already in use"));
+                }
+                table.channels.insert(id, slot.clone());
+                drop(table);
+                self.send_frame(CONFIRM, id, &INITIAL_WINDOW.to_be_bytes())?;
+                let _ = incoming.send(Channel::new(id, slot, self.clone()));
+                continue;
+            }
+
+            // like in ssh the frames of a channel closed meanwhile are
+            // dropped, a Data or Window frame sent before the Close of the
+            // peer can arrive after our Close removed the channel
+            let slot = match self.table.lock().unwrap().channels.get(&id) {
+                Some(slot) => slot.clone(),
+                None => continue,
+            };
+            let mut state = slot.state.lock().unwrap();
+            match kind {
+                CONFIRM => {
+                    state.remote_window = read_u32(payload)?;
+                    state.confirmed = true;
+                }
+                DATA => {
+                    if payload.len() > state.local_window as usize {
+                        return Err(invalid("peer exceeded the channel window"));
+                    }
+                    state.local_window -= payload.len() as u32;
+                    // data after our Close is dropped
+                    if !state.close_sent {
+                        state.buffer.extend(payload);
+                    }
+                }
+                WINDOW => {
+                    let add = read_u32(payload)?;
+                    state.remote_window = state.remote_window.saturating_add(add);
+                }
+                CLOSE => state.close_received = true,
+                t => return Err(invalid(&format!("unknown frame type {}", t))),
+            }
+            let finished = state.close_sent && state.close_received;
+            drop(state);
+            slot.cond.notify_all();
+            if finished {
+                self.table.lock().unwrap().channels.remove(&id);
+            }
+        }
+    }
+
+    /// marks the connection as gone and wakes up every channel
+    fn shutdown(&self) {
+        let mut table = self.table.lock().unwrap();
+        table.closed = true;
+        for slot in table.channels.values() {
+            slot.state.lock().unwrap().dead = true;
+            slot.cond.notify_all();
+        }
+        table.channels.clear();
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl Slot {
+    fn new(confirmed: bool, remote_window: u32) -> Self {
+        Slot {
+            state: Mutex::new(State {
+                confirmed,
+                local_window: INITIAL_WINDOW,
+                remote_window,
+                ..State::default()
+            }),
+            cond: Condvar::new(),
+        }
+    }
+}
+
+/// one channel of the mux, clones refer to the same channel, so one
+/// thread can read while another writes. a write_all is never interleaved
+/// with the writes of other clones, so several threads can send messages
+/// on the same channel. the channel is closed when the last clone is
+/// dropped
+#[derive(Clone)]
+pub struct Channel {
+    inner: Arc<Handle>,
+}
+
+struct Handle {
+    id: u32,
+    slot: Arc<Slot>,
+    shared: Arc<Shared>,
+    write_lock: Mutex<()>,
+}
+
+impl Channel {
+    fn new(id: u32, slot: Arc<Slot>, shared: Arc<Shared>) -> Self {
+        Channel {
+            inner: Arc::new(Handle {
+                id,
+                slot,
+                shared,
+                write_lock: Mutex::new(()),
+            }),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.inner.id
+    }
+
+    /// closes the channel for all clones, blocked reads and writes return
+    /// an error
+    pub fn close(&self) {
+        self.inner.close();
+    }
+}
+
+impl Read for Channel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let h = &self.inner;
+        let mut state = h.slot.state.lock().unwrap();
+        if state.close_sent {
+            return Err(channel_closed());
+        }
+        while state.buffer.is_empty() {
+            if state.close_sent {
+                return Err(channel_closed());
+            }
+            if state.close_received {
+                return Ok(0);
+            }
+            if state.dead {
+                return Err(connection_lost());
+            }
+            state = h.slot.cond.wait(state).unwrap();
+        }
+        let len = buf.len().min(state.buffer.len());
+        for (b, v) in buf.iter_mut().zip(state.buffer.drain(..len)) {
+            *b = v;
+        }
+
+        state.consumed += len as u32;
+        if state.consumed < INITIAL_WINDOW / 2 || state.close_sent || state.close_received {
+            return Ok(len);
+        }
+        let add = state.consumed;
+        state.consumed = 0;
+        state.local_window += add;
+        drop(state);
+        h.shared.send_frame(WINDOW, h.id, &add.to_be_bytes())?;
+        Ok(len)
+    }
+}
+
+impl Handle {
+    fn close(&self) {
+        let mut state = self.slot.state.lock().unwrap();
+        if state.close_sent || state.dead {
+            return;
+        }
+        state.close_sent = true;
+        let finished = state.close_received;
+        drop(state);
+        self.slot.cond.notify_all();
+        let _ = self.shared.send_frame(CLOSE, self.id, &[]);
+        if finished {
+            self.shared.table.lock().unwrap().channels.remove(&self.id);
+        }
+    }
+
+    /// sends as much of buf as the remote window allows
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut state = self.slot.state.lock().unwrap();
+        loop {
+            if state.dead {
+                return Err(connection_lost());
+            }
+            if state.close_sent || state.close_received {
+                return Err(channel_closed());
+            }
+            if state.remote_window > 0 {
+                break;
+            }
+            state = self.slot.cond.wait(state).unwrap();
+        }
+        let len = buf.len().min(MAX_PACKET).min(state.remote_window as usize);
+        state.remote_window -= len as u32;
+        drop(state);
+        self.shared.send_frame(DATA, self.id, &buf[..len])?;
+        Ok(len)
+    }
+}
+
+impl Write for Channel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _guard = self.inner.write_lock.lock().unwrap();
+        self.inner.send(buf)
+    }
+
+    fn write_all(&mut self, mut buf: &[u8]) -> io::Result<()> {
+        let _guard = self.inner.write_lock.lock().unwrap();
+        while !buf.is_empty() {
+            let len = self.inner.send(buf)?;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// writes the queued frames until Finish or a failed write
+fn write_frames(
+    writer: &mut AeadWriter<TcpStream>,
+    queue: mpsc::Receiver<Outgoing>,
+) -> io::Result<()> {
+    for outgoing in queue {
+        match outgoing {
+            Outgoing::Frame(frame) => {
+                writer.write_all(&frame)?;
+                writer.flush()?;
+            }
+            Outgoing::Finish => return writer.finish(),
+        }
+    }
+    Ok(())
+}
+
+fn read_u32(payload: &[u8]) -> io::Result<u32> {
+    if payload.len() != 4 {
+        return Err(invalid("invalid frame payload"));
+    }
+    Ok(u32::from_be_bytes([
+        payload[0], payload[1], payload[2], payload[3],
+    ]))
+}
+
+fn channel_closed() -> io::Error {
+    io::Error::new(ErrorKind::BrokenPipe, "channel closed")
+}
+
+fn connection_lost() -> io::Error {
+    io::Error::new(ErrorKind::ConnectionAborted, "connection lost")
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cryptolib_aead::{KEY_SIZE, PREFIX_SIZE};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    const KEY: [u8; KEY_SIZE] = [5; KEY_SIZE];
+    const CLIENT_PREFIX: [u8; PREFIX_SIZE] = [1; PREFIX_SIZE];
+    const SERVER_PREFIX: [u8; PREFIX_SIZE] = [2; PREFIX_SIZE];
+
+    /// a client and a server mux connected over a local socket
+    fn pair() -> (Mux, Mux) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let client = Mux::client(
+            AeadWriter::new(&KEY, CLIENT_PREFIX, client.try_clone().unwrap()).unwrap(),
+            AeadReader::new(&KEY, SERVER_PREFIX, client).unwrap(),
+        )
+        .unwrap();
+        let server = Mux::server(
+            AeadWriter::new(&KEY, SERVER_PREFIX, server.try_clone().unwrap()).unwrap(),
+            AeadReader::new(&KEY, CLIENT_PREFIX, server).unwrap(),
+        )
+        .unwrap();
+        (client, server)
+    }
+
+    fn remote_window(channel: &Channel) -> u32 {
+        channel.inner.slot.state.lock().unwrap().remote_window
+    }
+
+    fn channels(mux: &Mux) -> usize {
+        mux.shared.table.lock().unwrap().channels.len()
+    }
+
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        let started = Instant::now();
+        while !condition() {
+            assert!(started.elapsed() < Duration::from_secs(5), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn ping(from: &mut Channel, to: &mut Channel) {
+        from.write_all(b"ping").unwrap();
+        let mut buf = [0; 4];
+        to.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    /// fills the window of channel, returns a receiver of the result of a
+    /// write that blocks until the peer consumes data
+    fn stall(channel: &mut Channel) -> mpsc::Receiver<io::Result<()>> {
+        channel
+            .write_all(&vec![0; INITIAL_WINDOW as usize])
+            .unwrap();
+        assert_eq!(remote_window(channel), 0);
+        let mut blocked = channel.clone();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = tx.send(blocked.write_all(b"more"));
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        rx
+    }
+
+    #[test]
+    fn open_is_confirmed() {
+        let (client, server) = pair();
+        let mut opened = client.open().unwrap();
+        let mut accepted = server.accept().unwrap();
+        assert_eq!(opened.id(), 1);
+        assert_eq!(accepted.id(), 1);
+        assert_eq!(remote_window(&opened), INITIAL_WINDOW);
+        ping(&mut opened, &mut accepted);
+        ping(&mut accepted, &mut opened);
+
+        let mut opened = server.open().unwrap();
+        let mut accepted = client.accept().unwrap();
+        assert_eq!(opened.id(), 2);
+        assert_eq!(accepted.id(), 2);
+        ping(&mut opened, &mut accepted);
+    }
+
+    #[test]
+    fn window_is_refilled() {
+        let (client, server) = pair();
+        let mut sender = client.open().unwrap();
+        let mut receiver = server.accept().unwrap();
+        let blocked = stall(&mut sender);
+        let mut buf = vec![0; INITIAL_WINDOW as usize / 2];
+        receiver.read_exact(&mut buf).unwrap();
+        blocked
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+        let mut rest = vec![0; INITIAL_WINDOW as usize / 2 + 4];
+        receiver.read_exact(&mut rest).unwrap();
+        assert!(rest.ends_with(b"more"));
+    }
+
+    #[test]
+    fn stalled_channel_does_not_block_others() {
+        let (client, server) = pair();
+        let mut stalled = client.open().unwrap();
+        let mut stalled_peer = server.accept().unwrap();
+        let blocked = stall(&mut stalled);
+
+        let mut opened = client.open().unwrap();
+        let mut accepted = server.accept().unwrap();
+        ping(&mut opened, &mut accepted);
+        ping(&mut accepted, &mut opened);
+
+        let mut buf = vec![0; INITIAL_WINDOW as usize + 4];
+        stalled_peer.read_exact(&mut buf).unwrap();
+        blocked
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap();
+    }
+
+    fn close_from(client_closes: bool) {
+        let (client, server) = pair();
+        let opened = client.open().unwrap();
+        let accepted = server.accept().unwrap();
+        let (mut closer, mut peer) = if client_closes {
+            (opened, accepted)
+        } else {
+            (accepted, opened)
+        };
+        closer.close();
+        let mut buf = [0; 4];
+        assert_eq!(peer.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            peer.write(b"late").unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+        assert_eq!(
+            closer.read(&mut buf).unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+        assert_eq!(
+            closer.write(b"late").unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+        // half closed, both sides still know the channel
+        assert_eq!((channels(&client), channels(&server)), (1, 1));
+        peer.close();
+        wait_until(|| channels(&client) == 0 && channels(&server) == 0);
+    }
+
+    #[test]
+    fn client_closes() {
+        close_from(true);
+    }
+
+    #[test]
+    fn server_closes() {
+        close_from(false);
+    }
+
+    #[test]
+    fn frames_for_removed_channels_are_dropped() {
+        let (client, server) = pair();
+        let opened = client.open().unwrap();
+        let id = opened.id();
+        drop(opened);
+        drop(server.accept().unwrap());
+        wait_until(|| channels(&client) == 0 && channels(&server) == 0);
+
+        client.shared.send_frame(DATA, id, b"late").unwrap();
+        client
+            .shared
+            .send_frame(WINDOW, id, &1024u32.to_be_bytes())
+            .unwrap();
+        client.shared.send_frame(CLOSE, 99, &[]).unwrap();
+        let mut opened = client.open().unwrap();
+        let mut accepted = server.accept().unwrap();
+        ping(&mut opened, &mut accepted);
+        assert!(!server.is_closed());
+    }
+
+    #[test]
+    fn lost_connection_wakes_blocked_channels() {
+        let (client, server) = pair();
+        let mut reading = client.open().unwrap();
+        let _reading_peer = server.accept().unwrap();
+        let mut writing = client.open().unwrap();
+        let _writing_peer = server.accept().unwrap();
+
+        let blocked_write = stall(&mut writing);
+        let (tx, blocked_read) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 4];
+            let _ = tx.send(reading.read(&mut buf));
+        });
+        assert!(blocked_read
+            .recv_timeout(Duration::from_millis(200))
+            .is_err());
+
+        // the peer vanishes without finishing the stream
+        server.shared.stream.shutdown(Shutdown::Both).unwrap();
+        let err = blocked_read
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+        let err = blocked_write
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+        wait_until(|| client.is_closed());
+        assert!(client.open().is_err());
+    }
+}
@@ -0,0 +1,942 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! message protocol spoken by trsh-server and trsh-client on top of
+//! the encrypted channel
+//!
+//! every message is framed as
+//!
+//! <u32 length> <u8 type> <fields>
+//!
+//! strings and byte fields are encoded as <u32 length> <bytes>, integers
+//! are big endian. lists are encoded as <u32 count> <items>, optional
+//! fields as <u8 present> <field>. a connection starts with Hello from the
+//! client, then the server sends Exec, ExecArgv, Get, Put, GetTree, PutTree
+//! or Shell requests one after another until it ends the session with
+//! Close. the receiver of a file answers Get or Put with Resume, then the
+//! file contents are sent as FileHeader followed by Data messages ending
+//! with Eof and the Digest of the contents. GetTree and PutTree send a
+//! directory tree as Entry messages followed by a Skipped message for every
+//! entry the sender could not read and Eof, the Entry of a file is followed
+//! by Data messages ending with Eof and its Digest. shell output
+//! is sent as Data messages ending with Eof. the shell input may carry
+//! Resize and Signal messages between its Data. the shell output is
+//! followed by ExitStatus or ExitSignal of the shell, then the server ends
+//! the shell input. the server sends the stdin of Exec and ExecArgv the
+//! same way, the output is sent as Data for stdout and Stderr for stderr,
+//! ending with ExitStatus or ExitSignal. the server can send Kill on the
+//! channel of the command at any time to terminate the command. the
+//! requests carry the Compression of their file contents and command
+//! output, a compressed stream is sent as Data messages like a plain one.
+use std::io;
+use std::{
+    fmt,
+    io::{ErrorKind, Read, Write},
+    sync::mpsc,
+};
+
+use crate::compress::{Compressor, Decompressor};
+
+/// version of the handshake and message format, both sides have to speak the
+/// same version. bump it on every incompatible change
+pub const PROTOCOL_VERSION: u16 = 18;
+
+/// capability bits exchanged in the handshake, a capability is used when
+/// both sides announce it
+pub const CAP_XCHACHA20_POLY1305: u32 = 1 << 0;
+pub const CAP_ZSTD: u32 = 1 << 1;
+pub const CAP_DEFLATE: u32 = 1 << 2;
+
+/// capabilities of this build
+pub const CAPABILITIES: u32 = CAP_XCHACHA20_POLY1305 | CAP_ZSTD | CAP_DEFLATE;
+
+/// largest accepted message
+pub const MAX_MESSAGE: usize = 1024 * 1024;
+/// payload size of the Data messages sent by send_stream
+pub const DATA_CHUNK: usize = 8 * 1024;
+
+const HELLO: u8 = 1;
+const EXEC: u8 = 2;
+const GET: u8 = 3;
+const PUT: u8 = 4;
+const SHELL: u8 = 5;
+const DATA: u8 = 6;
+const EOF: u8 = 7;
+const EXIT_STATUS: u8 = 8;
+const ERROR: u8 = 9;
+const CLOSE: u8 = 10;
+const STDERR: u8 = 11;
+const EXIT_SIGNAL: u8 = 12;
+const KILL: u8 = 13;
+const EXEC_ARGV: u8 = 14;
+const RESIZE: u8 = 15;
+const SIGNAL: u8 = 16;
+const FILE_HEADER: u8 = 17;
+const DIGEST: u8 = 18;
+const RESUME: u8 = 19;
+const GET_TREE: u8 = 20;
+const PUT_TREE: u8 = 21;
+const ENTRY: u8 = 22;
+const SKIPPED: u8 = 23;
+
+const ENTRY_DIR: u8 = 1;
+const ENTRY_FILE: u8 = 2;
+const ENTRY_SYMLINK: u8 = 3;
+
+const COMPRESS_NONE: u8 = 0;
+const COMPRESS_ZSTD: u8 = 1;
+const COMPRESS_DEFLATE: u8 = 2;
+
+/// metadata of a transferred file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileMeta {
+    pub size: u64,
+    /// permission bits including setuid, setgid and sticky
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// modification time in seconds since the epoch
+    pub mtime: i64,
+}
+
+/// type of an Entry of a tree
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryKind {
+    Dir,
+    File,
+    /// a symbolic link with its target, it is not followed
+    Symlink(String),
+}
+
+/// compression of the data of a request
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Deflate,
+}
+
+impl Compression {
+    /// the best compression of the common capabilities of a session, zstd
+    /// compresses better and faster than deflate
+    pub fn negotiate(caps: u32) -> Compression {
+        if caps & CAP_ZSTD != 0 {
+            Compression::Zstd
+        } else if caps & CAP_DEFLATE != 0 {
+            Compression::Deflate
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Deflate => "deflate",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// first message of the client
+    Hello { id: String },
+    /// runs a command on the client with /bin/sh -c, its output is sent
+    /// with compression
+    Exec {
+        command: String,
+        compression: Compression,
+    },
+    /// runs argv on the client without a shell, in cwd and with the
+    /// additional environment variables of env
+    ExecArgv {
+        argv: Vec<String>,
+        cwd: Option<String>,
+        env: Vec<(String, String)>,
+        compression: Compression,
+    },
+    /// transfers a file from the client to the server, the contents are sent
+    /// with compression
+    Get {
+        path: String,
+        compression: Compression,
+    },
+    /// transfers a file from the server to the client
+    Put {
+        name: String,
+        target_dir: String,
+        compression: Compression,
+    },
+    /// transfers the directory tree at path from the client to the server,
+    /// without the files matching exclude and with only the files matching
+    /// include unless it is empty. the patterns are globs
+    GetTree {
+        path: String,
+        include: Vec<String>,
+        exclude: Vec<String>,
+        compression: Compression,
+    },
+    /// transfers a directory tree from the server into target_dir of the
+    /// client
+    PutTree {
+        target_dir: String,
+        compression: Compression,
+    },
+    /// allocates an interactive shell running program with args, in cwd and
+    /// with the additional environment variables of env. without program the
+    /// client picks its shell and passes args to it. term is the TERM of the server and modes are
+    /// its terminal modes encoded by tty_modes
+    Shell {
+        cols: u16,
+        rows: u16,
+        program: Option<String>,
+        args: Vec<String>,
+        env: Vec<(String, String)>,
+        cwd: Option<String>,
+        term: Option<String>,
+        modes: Vec<(u8, u32)>,
+    },
+    /// new window size of a shell, sent by the server between the Data of
+    /// the shell input
+    Resize { cols: u16, rows: u16 },
+    /// signal for the foreground process of a shell, sent like Resize
+    Signal(i32),
+    /// length and SHA-256 of the partial file of the receiver, offset 0 when
+    /// it has none
+    Resume { offset: u64, digest: Vec<u8> },
+    /// metadata of a file, sent before its contents starting at offset
+    FileHeader { meta: FileMeta, offset: u64 },
+    /// a directory, file or symlink of a tree, path is relative to the
+    /// target directory
+    Entry {
+        path: String,
+        kind: EntryKind,
+        meta: FileMeta,
+    },
+    /// an entry of a tree the sender could not read, with the reason
+    Skipped(String),
+    /// a chunk of a file, of command output or of shell io
+    Data(Vec<u8>),
+    /// end of the data stream
+    Eof,
+    /// SHA-256 of a file, sent after its Eof
+    Digest(Vec<u8>),
+    /// stderr output of a remote command
+    Stderr(Vec<u8>),
+    /// exit code of a remote command
+    ExitStatus(i32),
+    /// signal that terminated a remote command
+    ExitSignal(i32),
+    /// the request failed on the remote side
+    Error(String),
+    /// ends the session
+    Close,
+    /// terminates a running command, sent when it timed out
+    Kill,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        match self {
+            Message::Hello { id } => {
+                buf.push(HELLO);
+                put_bytes(&mut buf, id.as_bytes());
+            }
+            Message::Exec {
+                command,
+                compression,
+            } => {
+                buf.push(EXEC);
+                put_bytes(&mut buf, command.as_bytes());
+                put_compression(&mut buf, *compression);
+            }
+            Message::ExecArgv {
+                argv,
+                cwd,
+                env,
+                compression,
+            } => {
+                buf.push(EXEC_ARGV);
+                put_strings(&mut buf, argv);
+                put_option(&mut buf, cwd);
+                put_env(&mut buf, env);
+                put_compression(&mut buf, *compression);
+            }
+            Message::Get { path, compression } => {
+                buf.push(GET);
+                put_bytes(&mut buf, path.as_bytes());
+                put_compression(&mut buf, *compression);
+            }
+            Message::Put {
+                name,
+                target_dir,
+                compression,
+            } => {
+                buf.push(PUT);
+                put_bytes(&mut buf, name.as_bytes());
+                put_bytes(&mut buf, target_dir.as_bytes());
+                put_compression(&mut buf, *compression);
+            }
+            Message::GetTree {
+                path,
+                include,
+                exclude,
+                compression,
+            } => {
+                buf.push(GET_TREE);
+                put_bytes(&mut buf, path.as_bytes());
+                put_strings(&mut buf, include);
+                put_strings(&mut buf, exclude);
+                put_compression(&mut buf, *compression);
+            }
+            Message::PutTree {
+                target_dir,
+                compression,
+            } => {
+                buf.push(PUT_TREE);
+                put_bytes(&mut buf, target_dir.as_bytes());
+                put_compression(&mut buf, *compression);
+            }
+            Message::Shell {
+                cols,
+                rows,
+                program,
+                args,
+                env,
+                cwd,
+                term,
+                modes,
+            } => {
+                buf.push(SHELL);
+                buf.extend_from_slice(&cols.to_be_bytes());
+                buf.extend_from_slice(&rows.to_be_bytes());
+                put_option(&mut buf, program);
+                put_strings(&mut buf, args);
+                put_env(&mut buf, env);
+                put_option(&mut buf, cwd);
+                put_option(&mut buf, term);
+                buf.extend_from_slice(&(modes.len() as u32).to_be_bytes());
+                for (op, value) in modes {
+                    buf.push(*op);
+                    buf.extend_from_slice(&value.to_be_bytes());
+                }
+            }
+            Message::Resize { cols, rows } => {
+                buf.push(RESIZE);
+                buf.extend_from_slice(&cols.to_be_bytes());
+                buf.extend_from_slice(&rows.to_be_bytes());
+            }
+            Message::Signal(signal) => {
+                buf.push(SIGNAL);
+                buf.extend_from_slice(&signal.to_be_bytes());
+            }
+            Message::Resume { offset, digest } => {
+                buf.push(RESUME);
+                buf.extend_from_slice(&offset.to_be_bytes());
+                put_bytes(&mut buf, digest);
+            }
+            Message::FileHeader { meta, offset } => {
+                buf.push(FILE_HEADER);
+                put_meta(&mut buf, meta);
+                buf.extend_from_slice(&offset.to_be_bytes());
+            }
+            Message::Entry { path, kind, meta } => {
+                buf.push(ENTRY);
+                put_bytes(&mut buf, path.as_bytes());
+                match kind {
+                    EntryKind::Dir => buf.push(ENTRY_DIR),
+                    EntryKind::File => buf.push(ENTRY_FILE),
+                    EntryKind::Symlink(target) => {
+                        buf.push(ENTRY_SYMLINK);
+                        put_bytes(&mut buf, target.as_bytes());
+                    }
+                }
+                put_meta(&mut buf, meta);
+            }
+            Message::Skipped(entry) => {
+                buf.push(SKIPPED);
+                put_bytes(&mut buf, entry.as_bytes());
+            }
+            Message::Data(data) => {
+                buf.push(DATA);
+                put_bytes(&mut buf, data);
+            }
+            Message::Eof => buf.push(EOF),
+            Message::Digest(digest) => {
+                buf.push(DIGEST);
+                put_bytes(&mut buf, digest);
+            }
+            Message::Stderr(data) => {
+                buf.push(STDERR);
+                put_bytes(&mut buf, data);
+            }
+            Message::ExitStatus(code) => {
+                buf.push(EXIT_STATUS);
+                buf.extend_from_slice(&code.to_be_bytes());
+            }
+            Message::ExitSignal(signal) => {
+                buf.push(EXIT_SIGNAL);
+                buf.extend_from_slice(&signal.to_be_bytes());
+            }
+            Message::Error(msg) => {
+                buf.push(ERROR);
+                put_bytes(&mut buf, msg.as_bytes());
+            }
+            Message::Close => buf.push(CLOSE),
+            Message::Kill => buf.push(KILL),
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> io::Result<Message> {
+        let mut d = Decoder { buf, pos: 0 };
+        let msg = match d.u8()? {
+            HELLO => Message::Hello { id: d.string()? },
+            EXEC => Message::Exec {
+                command: d.string()?,
+                compression: d.compression()?,
+            },
+            EXEC_ARGV => Message::ExecArgv {
+                argv: d.strings()?,
+                cwd: d.option()?,
+                env: d.env()?,
+                compression: d.compression()?,
+            },
+            GET => Message::Get {
+                path: d.string()?,
+                compression: d.compression()?,
+            },
+            PUT => Message::Put {
+                name: d.string()?,
+                target_dir: d.string()?,
+                compression: d.compression()?,
+            },
+            GET_TREE => Message::GetTree {
+                path: d.string()?,
+                include: d.strings()?,
+                exclude: d.strings()?,
+                compression: d.compression()?,
+            },
+            PUT_TREE => Message::PutTree {
+                target_dir: d.string()?,
+                compression: d.compression()?,
+            },
+            SHELL => Message::Shell {
+                cols: d.u16()?,
+                rows: d.u16()?,
+                program: d.option()?,
+                args: d.strings()?,
+                env: d.env()?,
+                cwd: d.option()?,
+                term: d.option()?,
+                modes: d.modes()?,
+            },
+            RESIZE => Message::Resize {
+                cols: d.u16()?,
+                rows: d.u16()?,
+            },
+            SIGNAL => Message::Signal(d.u32()? as i32),
+            RESUME => Message::Resume {
+                offset: d.u64()?,
+                digest: d.bytes()?.to_vec(),
+            },
+            FILE_HEADER => Message::FileHeader {
+                meta: d.meta()?,
+                offset: d.u64()?,
+            },
+            ENTRY => Message::Entry {
+                path: d.string()?,
+                kind: match d.u8()? {
+                    ENTRY_DIR => EntryKind::Dir,
+                    ENTRY_FILE => EntryKind::File,
+                    ENTRY_SYMLINK => EntryKind::Symlink(d.string()?),
+                    k => return Err(invalid(&format!("unknown entry type {}", k))),
+                },
+                meta: d.meta()?,
+            },
+            SKIPPED => Message::Skipped(d.string()?),
+            DATA => Message::Data(d.bytes()?.to_vec()),
+            EOF => Message::Eof,
+            DIGEST => Message::Digest(d.bytes()?.to_vec()),
+            STDERR => Message::Stderr(d.bytes()?.to_vec()),
+            EXIT_STATUS => Message::ExitStatus(d.u32()? as i32),
+            EXIT_SIGNAL => Message::ExitSignal(d.u32()? as i32),
+            ERROR => Message::Error(d.string()?),
+            CLOSE => Message::Close,
+            KILL => Message::Kill,
+            t => return Err(invalid(&format!("unknown message type {}", t))),
+        };
+        if d.pos != buf.len() {
+            return Err(invalid("trailing bytes in message"));
+        }
+        Ok(msg)
+    }
+
+    /// name of the message type for log output
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello { .. } => "Hello",
+            Message::Exec { .. } => "Exec",
+            Message::ExecArgv { .. } => "ExecArgv",
+            Message::Get { .. } => "Get",
+            Message::Put { .. } => "Put",
+            Message::GetTree { .. } => "GetTree",
+            Message::PutTree { .. } => "PutTree",
+            Message::Shell { .. } => "Shell",
+            Message::Resize { .. } => "Resize",
+            Message::Signal(_) => "Signal",
+            Message::Resume { .. } => "Resume",
+            Message::FileHeader { .. } => "FileHeader",
+            Message::Entry { .. } => "Entry",
+            Message::Skipped(_) => "Skipped",
+            Message::Data(_) => "Data",
+            Message::Eof => "Eof",
+            Message::Digest(_) => "Digest",
+            Message::Stderr(_) => "Stderr",
+            Message::ExitStatus(_) => "ExitStatus",
+            Message::ExitSignal(_) => "ExitSignal",
+            Message::Error(_) => "Error",
+            Message::Close => "Close",
+            Message::Kill => "Kill",
+        }
+    }
+}
+
+/// writes one framed message
+pub fn write_message<W: Write + ?Sized>(writer: &mut W, msg: &Message) -> io::Result<()> {
+    let body = msg.encode();
+    if body.len() > MAX_MESSAGE {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "message too large"));
+    }
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// reads one framed message, no matter how it was split on the wire
+pub fn read_message<R: Read + ?Sized>(reader: &mut R) -> io::Result<Message> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_be_bytes(header) as usize;
+    if len == 0 || len > MAX_MESSAGE {
+        return Err(invalid("invalid message length"));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Message::decode(&body)
+}
+
+/// sends everything read from reader as Data messages followed by Eof,
+/// returns the number of bytes sent and reports the progress to tx
+pub fn send_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    tx: Option<&mpsc::Sender<u64>>,
+) -> io::Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    send_compressed(reader, writer, &mut Compressor::new(Compression::None)?, tx)
+}
+
+/// sends everything read from reader like send_stream, compressed by
+/// compressor. the number of bytes and the progress are counted before
+/// the compression
+pub fn send_compressed<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    compressor: &mut Compressor,
+    tx: Option<&mpsc::Sender<u64>>,
+) -> io::Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut buffer = vec![0; DATA_CHUNK];
+
+    let mut written = 0;
+    loop {
+        let len = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        write_message(writer, &Message::Data(compressor.compress(&buffer[..len])?))?;
+        written += len as u64;
+        if let Some(tx) = tx {
+            let _ = tx.send(written);
+        }
+    }
+    write_message(writer, &Message::Eof)?;
+    Ok(written)
+}
+
+/// writes the Data messages read from reader to writer until Eof,
+/// returns the number of bytes received and reports the progress to tx.
+/// an Error message from the peer is returned as io::Error
+pub fn recv_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    tx: Option<&mpsc::Sender<u64>>,
+) -> io::Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    recv_compressed(
+        reader,
+        writer,
+        &mut Decompressor::new(Compression::None)?,
+        tx,
+    )
+}
+
+/// receives a stream sent by send_compressed like recv_stream, the data is
+/// decompressed by decompressor
+pub fn recv_compressed<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    decompressor: &mut Decompressor,
+    tx: Option<&mpsc::Sender<u64>>,
+) -> io::Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut written = 0;
+    loop {
+        match read_message(reader)? {
+            Message::Data(data) => {
+                let data = decompressor.decompress(&data)?;
+                writer.write_all(&data)?;
+                writer.flush()?;
+                written += data.len() as u64;
+                if let Some(tx) = tx {
+                    let _ = tx.send(written);
+                }
+            }
+            Message::Eof => return Ok(written),
+            Message::Error(e) => return Err(io::Error::other(e)),
+            msg => return Err(unexpected(&msg)),
+        }
+    }
+}
+
+/// error for a message that is not valid at this point of the protocol
+pub fn unexpected(msg: &Message) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("unexpected message {}", msg.name()),
+    )
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn put_strings(buf: &mut Vec<u8>, strings: &[String]) {
+    buf.extend_from_slice(&(strings.len() as u32).to_be_bytes());
+    for string in strings {
+        put_bytes(buf, string.as_bytes());
+    }
+}
+
+fn put_option(buf: &mut Vec<u8>, string: &Option<String>) {
+    match string {
+        Some(string) => {
+            buf.push(1);
+            put_bytes(buf, string.as_bytes());
+        }
+        None => buf.push(0),
+    }
+}
+
+fn put_env(buf: &mut Vec<u8>, env: &[(String, String)]) {
+    buf.extend_from_slice(&(env.len() as u32).to_be_bytes());
+    for (key, value) in env {
+        put_bytes(buf, key.as_bytes());
+        put_bytes(buf, value.as_bytes());
+    }
+}
+
+fn put_compression(buf: &mut Vec<u8>, compression: Compression) {
+    buf.push(match compression {
+        Compression::None => COMPRESS_NONE,
+        Compression::Zstd => COMPRESS_ZSTD,
+        Compression::Deflate => COMPRESS_DEFLATE,
+    });
+}
+
+fn put_meta(buf: &mut Vec<u8>, meta: &FileMeta) {
+    buf.extend_from_slice(&meta.size.to_be_bytes());
+    buf.extend_from_slice(&meta.mode.to_be_bytes());
+    buf.extend_from_slice(&meta.uid.to_be_bytes());
+    buf.extend_from_slice(&meta.gid.to_be_bytes());
+    buf.extend_from_slice(&meta.mtime.to_be_bytes());
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() - self.pos < len {
+            return Err(invalid("truncated message"));
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.take(2)?);
+        Ok(u16::from_be_bytes(b))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(b))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(b))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid("invalid utf-8 in message"))
+    }
+
+    fn strings(&mut self) -> io::Result<Vec<String>> {
+        (0..self.u32()?).map(|_| self.string()).collect()
+    }
+
+    fn option(&mut self) -> io::Result<Option<String>> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.string()?)),
+        }
+    }
+
+    fn modes(&mut self) -> io::Result<Vec<(u8, u32)>> {
+        (0..self.u32()?)
+            .map(|_| Ok((self.u8()?, self.u32()?)))
+            .collect()
+    }
+
+    fn meta(&mut self) -> io::Result<FileMeta> {
+        Ok(FileMeta {
+            size: self.u64()?,
+            mode: self.u32()?,
+            uid: self.u32()?,
+            gid: self.u32()?,
+            mtime: self.u64()? as i64,
+        })
+    }
+
+    fn compression(&mut self) -> io::Result<Compression> {
+        match self.u8()? {
+            COMPRESS_NONE => Ok(Compression::None),
+            COMPRESS_ZSTD => Ok(Compression::Zstd),
+            COMPRESS_DEFLATE => Ok(Compression::Deflate),
+            c => Err(invalid(&format!("unknown compression {}", c))),
+        }
+    }
+
+    fn env(&mut self) -> io::Result<Vec<(String, String)>> {
+        (0..self.u32()?)
+            .map(|_| Ok((self.string()?, self.string()?)))
+            .collect()
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> FileMeta {
+        FileMeta {
+            size: 1 << 40,
+            mode: 0o4755,
+            uid: 1000,
+            gid: 100,
+            mtime: -86400,
+        }
+    }
+
+    fn messages() -> Vec<Message> {
+        let command = format!("{} | sort | uniq -c", "echo a|b; ".repeat(200));
+        assert!(command.len() > 1024);
+        vec![
+            Message::Hello {
+                id: "client".to_string(),
+            },
+            Message::Exec {
+                command,
+                compression: Compression::Zstd,
+            },
+            Message::ExecArgv {
+                argv: vec!["grep".to_string(), "a|b".to_string(), "".to_string()],
+                cwd: Some("/tmp".to_string()),
+                env: vec![("LC_ALL".to_string(), "C".to_string())],
+                compression: Compression::None,
+            },
+            Message::Get {
+                path: "/etc/hosts".to_string(),
+                compression: Compression::Deflate,
+            },
+            Message::Put {
+                name: "tool".to_string(),
+                target_dir: "/tmp".to_string(),
+                compression: Compression::Zstd,
+            },
+            Message::GetTree {
+                path: "/etc/nginx".to_string(),
+                include: vec!["*.conf".to_string()],
+                exclude: vec![],
+                compression: Compression::None,
+            },
+            Message::PutTree {
+                target_dir: "/tmp".to_string(),
+                compression: Compression::Deflate,
+            },
+            Message::Shell {
+                cols: 80,
+                rows: 24,
+                program: None,
+                args: vec!["-l".to_string()],
+                env: vec![],
+                cwd: None,
+                term: Some("xterm".to_string()),
+                modes: vec![(1, 3), (53, 1)],
+            },
+            Message::Resize {
+                cols: 200,
+                rows: 50,
+            },
+            Message::Signal(2),
+            Message::Resume {
+                offset: 4096,
+                digest: vec![0xab; 32],
+            },
+            Message::FileHeader {
+                meta: meta(),
+                offset: 0,
+            },
+            Message::Entry {
+                path: "tree/dir".to_string(),
+                kind: EntryKind::Dir,
+                meta: meta(),
+            },
+            Message::Entry {
+                path: "tree/file".to_string(),
+                kind: EntryKind::File,
+                meta: meta(),
+            },
+            Message::Entry {
+                path: "tree/link".to_string(),
+                kind: EntryKind::Symlink("../file".to_string()),
+                meta: meta(),
+            },
+            Message::Skipped("tree/fifo: not a regular file".to_string()),
+            Message::Data(vec![0, 1, 2, 255]),
+            Message::Eof,
+            Message::Digest(vec![0xcd; 32]),
+            Message::Stderr(b"error\n".to_vec()),
+            Message::ExitStatus(-1),
+            Message::ExitSignal(9),
+            Message::Error("failed".to_string()),
+            Message::Close,
+            Message::Kill,
+        ]
+    }
+
+    /// returns one byte per read, like a stream split into tiny segments
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        for msg in messages() {
+            assert_eq!(Message::decode(&msg.encode()).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn split_messages_are_read_whole() {
+        let mut wire = vec![];
+        for msg in messages() {
+            write_message(&mut wire, &msg).unwrap();
+        }
+        let mut reader = Trickle(&wire);
+        for msg in messages() {
+            assert_eq!(read_message(&mut reader).unwrap(), msg);
+        }
+        let err = read_message(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn truncated_body_is_rejected() {
+        for msg in messages() {
+            let body = msg.encode();
+            if body.len() < 2 {
+                continue;
+            }
+            let err = Message::decode(&body[..body.len() - 1]).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", msg.name());
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        for msg in messages() {
+            let mut body = msg.encode();
+            body.push(0);
+            let err = Message::decode(&body).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{}", msg.name());
+        }
+    }
+}
@@ -0,0 +1,520 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! file transfers between trsh-server and trsh-client
+//!
+//! the receiver writes a file to <name>.part and renames it when the
+//! transfer is complete. it starts with Resume, the length and SHA-256 of a
+//! part file left by an interrupted transfer. when the prefix of the file
+//! has the same digest the sender continues at its end, otherwise at 0.
+//! the sender announces the size, mode, owner, mtime and start offset of a
+//! file with FileHeader, then sends its contents with send_stream followed
+//! by the SHA-256 digest of the whole file. the receiver checks the size and
+//! the digest and applies the metadata. a short transfer keeps the part
+//! file to be resumed, a corrupted one removes it. the contents are
+//! compressed as the request says, the size, offset and digest are the ones
+//! of the uncompressed file.
+use std::fmt;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::compress::{Compressor, Decompressor, Throughput};
+use crate::digest::{self, HashReader, HashWriter, DIGEST_LEN};
+use crate::protocol::{self, read_message, write_message, Compression, FileMeta, Message};
+
+/// result of a finished transfer
+pub struct Transferred {
+    /// bytes sent by this transfer
+    pub bytes: u64,
+    /// offset the transfer resumed at, 0 for a complete transfer
+    pub offset: u64,
+    /// digest of the whole file
+    pub digest: [u8; DIGEST_LEN],
+    /// compression and speed of the bytes sent by this transfer
+    pub throughput: Throughput,
+}
+
+impl fmt::Display for Transferred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes were transferred", self.bytes)?;
+        if self.offset > 0 {
+            write!(f, ", resumed at {}", self.offset)?;
+        }
+        write!(
+            f,
+            ", {}, sha256 {}",
+            self.throughput,
+            digest::hex(&self.digest)
+        )
+    }
+}
+
+/// reads the metadata of an open file
+pub fn file_meta(file: &File) -> io::Result<FileMeta> {
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "not a regular file",
+        ));
+    }
+    Ok(to_file_meta(&meta))
+}
+
+pub(crate) fn to_file_meta(meta: &fs::Metadata) -> FileMeta {
+    FileMeta {
+        size: meta.len(),
+        mode: meta.mode() & 0o7777,
+        uid: meta.uid(),
+        gid: meta.gid(),
+        mtime: meta.mtime(),
+    }
+}
+
+/// sends the file at path with its header and digest after the Resume of
+/// the receiver, compresses its contents and reports the progress to tx.
+/// an error opening the file is sent to the peer as Error
+pub fn send_file<C: Read + Write + ?Sized>(
+    channel: &mut C,
+    path: &Path,
+    compression: Compression,
+    tx: Option<&Sender<u64>>,
+) -> io::Result<Transferred> {
+    let (resume, prefix) = match read_message(channel)? {
+        Message::Resume { offset, digest } => (offset, digest),
+        msg => return Err(protocol::unexpected(&msg)),
+    };
+    let opened = File::open(path).and_then(|file| {
+        let meta = file_meta(&file)?;
+        Ok((file, meta))
+    });
+    let (file, meta) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            let _ = write_message(
+                channel,
+                &Message::Error(format!("could not open {}: {}", path.display(), e)),
+            );
+            return Err(e);
+        }
+    };
+    let mut reader = HashReader::new(BufReader::new(file));
+    let mut offset = 0;
+    if resume > 0 && resume <= meta.size {
+        io::copy(&mut (&mut reader).take(resume), &mut io::sink())?;
+        if reader.digest()[..] == prefix[..] {
+            offset = resume;
+        } else {
+            reader = HashReader::new(BufReader::new(File::open(path)?));
+        }
+    }
+    write_message(
+        channel,
+        &Message::FileHeader {
+            meta: meta.clone(),
+            offset,
+        },
+    )?;
+    let mut compressor = Compressor::new(compression)?;
+    let len = send_contents(channel, &mut reader, meta.size, offset, &mut compressor, tx)?;
+    Ok(Transferred {
+        bytes: len,
+        offset,
+        digest: reader.digest(),
+        throughput: compressor.throughput(),
+    })
+}
+
+/// sends the contents of a file of size after offset followed by their
+/// Digest, the digest covers everything read through reader
+pub(crate) fn send_contents<R: Read, W: Write + ?Sized>(
+    writer: &mut W,
+    reader: &mut HashReader<R>,
+    size: u64,
+    offset: u64,
+    compressor: &mut Compressor,
+    tx: Option<&Sender<u64>>,
+) -> io::Result<u64> {
+    // a file growing meanwhile is cut at the announced size
+    let len = protocol::send_compressed(&mut reader.take(size - offset), writer, compressor, tx)?;
+    if offset + len != size {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("file shrank, {} of {} bytes sent", offset + len, size),
+        ));
+    }
+    write_message(writer, &Message::Digest(reader.digest().to_vec()))?;
+    Ok(len)
+}
+
+/// receives a file sent by send_file into path, verifies its digest and
+/// applies its metadata, reports the progress to tx. the part file of a
+/// previous transfer to path is resumed, a failed transfer leaves a part
+/// file to resume unless the received data is corrupted
+pub fn recv_file<C: Read + Write + ?Sized>(
+    channel: &mut C,
+    path: &Path,
+    compression: Compression,
+    tx: Option<&Sender<u64>>,
+) -> io::Result<Transferred> {
+    let part_path = part_path(path);
+    let (resume, prefix) = match File::open(&part_path) {
+        Ok(file) => {
+            let mut prefix = HashReader::new(BufReader::new(file));
+            let len = io::copy(&mut prefix, &mut io::sink())?;
+            (len, Some(prefix))
+        }
+        Err(_) => (0, None),
+    };
+    let digest = prefix.as_ref().map(|p| p.digest().to_vec());
+    write_message(
+        channel,
+        &Message::Resume {
+            offset: resume,
+            digest: digest.unwrap_or_default(),
+        },
+    )?;
+
+    let (meta, offset) = match read_message(channel)? {
+        Message::FileHeader { meta, offset } if offset == 0 || offset == resume => (meta, offset),
+        Message::FileHeader { offset, .. } => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid offset {}, {} bytes to resume", offset, resume),
+            ))
+        }
+        Message::Error(e) => return Err(io::Error::other(e)),
+        msg => return Err(protocol::unexpected(&msg)),
+    };
+    let file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(&part_path)
+        .and_then(|file| {
+            file.set_len(offset)?;
+            Ok(file)
+        });
+    let file = match file {
+        Ok(file) => file,
+        Err(e) => {
+            // the data is already on its way, drain it before reporting
+            let _ = protocol::recv_stream(channel, &mut io::sink(), None);
+            return Err(e);
+        }
+    };
+    let mut writer = match prefix {
+        Some(prefix) if offset > 0 => HashWriter::after(&file, prefix),
+        _ => HashWriter::new(&file),
+    };
+
+    let mut decompressor = Decompressor::new(compression)?;
+    let len = match recv_contents(
+        channel,
+        &mut writer,
+        meta.size,
+        offset,
+        &mut decompressor,
+        tx,
+    ) {
+        Ok(len) => len,
+        Err(e) => {
+            if file.metadata().map(|m| m.len() == 0).unwrap_or(true) {
+                drop(file);
+                let _ = fs::remove_file(&part_path);
+                return Err(e);
+            }
+            return Err(io::Error::new(
+                e.kind(),
+                format!("{}, {} kept to resume", e, part_path.display()),
+            ));
+        }
+    };
+
+    let digest = writer.digest();
+    let result = verify_digest(channel, &digest)
+        .and_then(|_| apply_meta(&file, &meta))
+        .and_then(|_| fs::rename(&part_path, path));
+    if let Err(e) = result {
+        drop(file);
+        let _ = fs::remove_file(&part_path);
+        return Err(e);
+    }
+    Ok(Transferred {
+        bytes: len,
+        offset,
+        digest,
+        throughput: decompressor.throughput(),
+    })
+}
+
+/// receives the contents of a file of size after offset, fails when they
+/// end short of size. the Digest following them is left to verify_digest
+pub(crate) fn recv_contents<R: Read + ?Sized, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    size: u64,
+    offset: u64,
+    decompressor: &mut Decompressor,
+    tx: Option<&Sender<u64>>,
+) -> io::Result<u64> {
+    let len = protocol::recv_compressed(reader, writer, decompressor, tx)?;
+    if offset + len != size {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!(
+                "short transfer, {} of {} bytes received",
+                offset + len,
+                size
+            ),
+        ));
+    }
+    Ok(len)
+}
+
+/// file a transfer to path is written to until it is complete
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+/// reads the Digest of the sender and compares it with digest
+pub(crate) fn verify_digest<R: Read + ?Sized>(reader: &mut R, digest: &[u8]) -> io::Result<()> {
+    match read_message(reader)? {
+        Message::Digest(sent) if sent == digest => Ok(()),
+        Message::Digest(sent) => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "digest mismatch, sha256 {} sent, {} received",
+                digest::hex(&sent),
+                digest::hex(digest)
+            ),
+        )),
+        Message::Error(e) => Err(io::Error::other(e)),
+        msg => Err(protocol::unexpected(&msg)),
+    }
+}
+
+/// applies owner, mode and mtime of meta to file. the owner is only changed
+/// where permitted, an unprivileged receiver keeps its own
+pub fn apply_meta(file: &File, meta: &FileMeta) -> io::Result<()> {
+    match std::os::unix::fs::fchown(file, Some(meta.uid), Some(meta.gid)) {
+        Err(e) if e.kind() != ErrorKind::PermissionDenied => return Err(e),
+        _ => {}
+    }
+    // after the chown, it clears setuid and setgid
+    file.set_permissions(Permissions::from_mode(meta.mode))?;
+    let mtime = if meta.mtime >= 0 {
+        UNIX_EPOCH + Duration::from_secs(meta.mtime as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(meta.mtime.unsigned_abs())
+    };
+    file.set_modified(mtime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("trsh-transfer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn meta(size: u64) -> FileMeta {
+        FileMeta {
+            size,
+            mode: 0o640,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        }
+    }
+
+    /// a channel replaying the messages of a sender and keeping the replies
+    struct Peer {
+        sent: io::Cursor<Vec<u8>>,
+        replies: Vec<u8>,
+    }
+
+    impl Peer {
+        fn new(messages: &[Message]) -> Peer {
+            let mut sent = vec![];
+            for msg in messages {
+                write_message(&mut sent, msg).unwrap();
+            }
+            Peer {
+                sent: io::Cursor::new(sent),
+                replies: vec![],
+            }
+        }
+    }
+
+    impl Read for Peer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.sent.read(buf)
+        }
+    }
+
+    impl Write for Peer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.replies.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// the first len bytes of a fixed pattern, any prefix is a prefix of longer contents
+    fn contents(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// sends source to target like a get or put, returns the results of
+    /// both sides
+    fn transfer(source: &Path, target: &Path) -> (Transferred, Transferred) {
+        let (mut sender, mut receiver) = UnixStream::pair().unwrap();
+        let source = source.to_path_buf();
+        let sending = thread::spawn(move || {
+            send_file(&mut sender, &source, Compression::Zstd, None).unwrap()
+        });
+        let received = recv_file(&mut receiver, target, Compression::Zstd, None).unwrap();
+        (sending.join().unwrap(), received)
+    }
+
+    fn recv_error(channel: &mut Peer, path: &Path) -> io::Error {
+        match recv_file(channel, path, Compression::None, None) {
+            Ok(done) => panic!("{} into {}", done, path.display()),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn short_transfer_fails() {
+        let dir = scratch("short");
+        let path = dir.join("file");
+        let mut channel = Peer::new(&[
+            Message::FileHeader {
+                meta: meta(100),
+                offset: 0,
+            },
+            Message::Data(vec![1; 60]),
+            Message::Eof,
+            Message::Digest(vec![0; DIGEST_LEN]),
+        ]);
+        let err = recv_error(&mut channel, &path);
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(
+            err.to_string()
+                .starts_with("short transfer, 60 of 100 bytes received"),
+            "{}",
+            err
+        );
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn digest_mismatch_is_rejected() {
+        let mut stream = vec![];
+        write_message(&mut stream, &Message::Digest(vec![0xab; DIGEST_LEN])).unwrap();
+        assert!(verify_digest(&mut &stream[..], &[0xab; DIGEST_LEN]).is_ok());
+
+        let err = verify_digest(&mut &stream[..], &[0xcd; DIGEST_LEN]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let expected = format!(
+            "digest mismatch, sha256 {} sent, {} received",
+            digest::hex(&[0xab; DIGEST_LEN]),
+            digest::hex(&[0xcd; DIGEST_LEN])
+        );
+        assert_eq!(err.to_string(), expected);
+    }
+
+    #[test]
+    fn matching_part_file_is_resumed() {
+        let dir = scratch("resume");
+        let (source, target) = (dir.join("source"), dir.join("target"));
+        fs::write(&source, contents(300_000)).unwrap();
+        fs::write(part_path(&target), &contents(300_000)[..100_000]).unwrap();
+        let (sent, received) = transfer(&source, &target);
+        assert_eq!((sent.offset, sent.bytes), (100_000, 200_000));
+        assert_eq!((received.offset, received.bytes), (100_000, 200_000));
+        assert_eq!(received.digest, sent.digest);
+        assert_eq!(fs::read(&target).unwrap(), contents(300_000));
+        assert!(!part_path(&target).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mismatched_part_file_is_restarted() {
+        let dir = scratch("restart");
+        let (source, target) = (dir.join("source"), dir.join("target"));
+        fs::write(&source, contents(300_000)).unwrap();
+        fs::write(part_path(&target), vec![0; 100_000]).unwrap();
+        let (sent, received) = transfer(&source, &target);
+        assert_eq!((sent.offset, sent.bytes), (0, 300_000));
+        assert_eq!((received.offset, received.bytes), (0, 300_000));
+        // the stale part file was truncated, not appended to
+        assert_eq!(fs::read(&target).unwrap(), contents(300_000));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_transfer_keeps_part_file() {
+        let dir = scratch("interrupted");
+        let (source, target) = (dir.join("source"), dir.join("target"));
+        fs::write(&source, contents(1000)).unwrap();
+        // the connection is lost after the first 400 bytes
+        let mut channel = Peer::new(&[
+            Message::FileHeader {
+                meta: meta(1000),
+                offset: 0,
+            },
+            Message::Data(contents(400)),
+        ]);
+        let err = recv_error(&mut channel, &target);
+        assert!(err.to_string().contains("kept to resume"), "{}", err);
+        assert_eq!(fs::read(part_path(&target)).unwrap(), contents(400));
+        assert!(!target.exists());
+
+        let (sent, received) = transfer(&source, &target);
+        assert_eq!(sent.offset, 400);
+        assert_eq!(received.offset, 400);
+        assert_eq!(fs::read(&target).unwrap(), contents(1000));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_transfer_removes_part_file() {
+        let dir = scratch("corrupted");
+        let target = dir.join("target");
+        let mut channel = Peer::new(&[
+            Message::FileHeader {
+                meta: meta(4),
+                offset: 0,
+            },
+            Message::Data(b"abcd".to_vec()),
+            Message::Eof,
+            Message::Digest(vec![0; DIGEST_LEN]),
+        ]);
+        let err = recv_error(&mut channel, &target);
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(!part_path(&target).exists());
+        assert!(!target.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
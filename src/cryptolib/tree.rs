@@ -0,0 +1,450 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! recursive transfers of directory trees
+//!
+//! the sender walks the tree and sends every directory, file and symlink as
+//! an Entry while it walks, nothing is archived first. paths are relative to
+//! the parent of the root, so the tree arrives under its own name in the
+//! target directory. symlinks are sent as links and never followed, files
+//! are followed by their contents and digest like in transfer. the receiver
+//! applies mode, owner and mtime, the ones of directories after their
+//! contents are written. the contents of all files are one compressed
+//! stream, so small files share the history of the compressor.
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::{symlink, OpenOptionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::Sender;
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::compress::{Compressor, Decompressor, Throughput};
+use crate::digest::{HashReader, HashWriter};
+use crate::protocol::{
+    self, read_message, write_message, Compression, EntryKind, FileMeta, Message,
+};
+use crate::transfer::{self, apply_meta, file_meta, to_file_meta};
+
+/// selects the files of a tree by their path relative to the root
+pub struct Filter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+}
+
+impl Filter {
+    /// without include every file is selected, exclude also prunes
+    /// directories
+    pub fn new(include: &[String], exclude: &[String]) -> io::Result<Filter> {
+        let include = if include.is_empty() {
+            None
+        } else {
+            Some(glob_set(include)?)
+        };
+        Ok(Filter {
+            include,
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    fn excluded(&self, path: &Path) -> bool {
+        self.exclude.is_match(path)
+    }
+
+    fn selected(&self, path: &Path) -> bool {
+        !self.excluded(path) && self.include.as_ref().is_none_or(|i| i.is_match(path))
+    }
+}
+
+fn glob_set(patterns: &[String]) -> io::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = Glob::new(pattern).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        builder.add(glob);
+    }
+    builder
+        .build()
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+}
+
+/// result of a finished tree transfer
+#[derive(Default)]
+pub struct TreeTransferred {
+    pub dirs: u64,
+    pub files: u64,
+    pub symlinks: u64,
+    /// bytes of the files
+    pub bytes: u64,
+    /// entries the sender could not read, with the reason
+    pub skipped: Vec<String>,
+    /// compression and speed of the contents of the files
+    pub throughput: Throughput,
+}
+
+impl fmt::Display for TreeTransferred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} directories, {} files and {} symlinks with {} bytes were transferred",
+            self.dirs, self.files, self.symlinks, self.bytes
+        )?;
+        write!(f, ", {}", self.throughput)?;
+        if !self.skipped.is_empty() {
+            write!(f, ", {} skipped", self.skipped.len())?;
+        }
+        Ok(())
+    }
+}
+
+/// sends the tree at root with the files selected by filter, compresses
+/// their contents and reports the progress of every file to tx. entries
+/// that can not be read are skipped, the receiver is told which before Eof
+pub fn send_tree<W: Write + ?Sized>(
+    writer: &mut W,
+    root: &Path,
+    filter: &Filter,
+    compression: Compression,
+    tx: Option<&Sender<u64>>,
+) -> io::Result<TreeTransferred> {
+    let name = match root.canonicalize()?.file_name() {
+        Some(name) => PathBuf::from(name),
+        None => {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} has no name", root.display()),
+            ))
+        }
+    };
+    let mut done = TreeTransferred::default();
+    let mut compressor = Compressor::new(compression)?;
+    let walker = WalkDir::new(root)
+        .follow_links(false)
+        .follow_root_links(true)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| entry.depth() == 0 || !filter.excluded(relative(root, entry.path())));
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                done.skipped.push(e.to_string());
+                continue;
+            }
+        };
+        let relative = relative(root, entry.path());
+        let path = match name.join(relative).to_str() {
+            Some(path) => path.to_string(),
+            None => {
+                done.skipped
+                    .push(format!("{}: invalid utf-8", entry.path().display()));
+                continue;
+            }
+        };
+        if !entry.file_type().is_dir() && entry.depth() > 0 && !filter.selected(relative) {
+            continue;
+        }
+        let source = match read_entry(&entry) {
+            Ok(source) => source,
+            Err(e) => {
+                done.skipped
+                    .push(format!("{}: {}", entry.path().display(), e));
+                continue;
+            }
+        };
+        match source {
+            Source::Dir(meta) => {
+                let kind = EntryKind::Dir;
+                write_message(writer, &Message::Entry { path, kind, meta })?;
+                done.dirs += 1;
+            }
+            Source::Symlink(target, meta) => {
+                let kind = EntryKind::Symlink(target);
+                write_message(writer, &Message::Entry { path, kind, meta })?;
+                done.symlinks += 1;
+            }
+            Source::File(file, meta) => {
+                let size = meta.size;
+                let kind = EntryKind::File;
+                write_message(writer, &Message::Entry { path, kind, meta })?;
+                let mut reader = HashReader::new(BufReader::new(file));
+                done.bytes +=
+                    transfer::send_contents(writer, &mut reader, size, 0, &mut compressor, tx)?;
+                done.files += 1;
+            }
+        }
+    }
+    for skipped in &done.skipped {
+        write_message(writer, &Message::Skipped(skipped.clone()))?;
+    }
+    write_message(writer, &Message::Eof)?;
+    done.throughput = compressor.throughput();
+    Ok(done)
+}
+
+fn relative<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root).unwrap_or(path)
+}
+
+/// an entry of the tree ready to be sent
+enum Source {
+    Dir(FileMeta),
+    Symlink(String, FileMeta),
+    File(File, FileMeta),
+}
+
+/// reads the metadata of an entry and opens a file, an error skips the entry
+fn read_entry(entry: &DirEntry) -> io::Result<Source> {
+    let file_type = entry.file_type();
+    if file_type.is_dir() {
+        Ok(Source::Dir(to_file_meta(&entry.metadata()?)))
+    } else if file_type.is_symlink() {
+        let meta = to_file_meta(&entry.metadata()?);
+        match fs::read_link(entry.path())?.into_os_string().into_string() {
+            Ok(target) => Ok(Source::Symlink(target, meta)),
+            Err(_) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "invalid utf-8 in target",
+            )),
+        }
+    } else if file_type.is_file() {
+        let file = File::open(entry.path())?;
+        let meta = file_meta(&file)?;
+        Ok(Source::File(file, meta))
+    } else {
+        // fifos, sockets and devices
+        Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "not a regular file",
+        ))
+    }
+}
+
+/// receives a tree sent by send_tree into target_dir, reports the progress
+/// of every file to tx. the transfer ends at the first entry that can not
+/// be written, the entries skipped by the sender are returned in skipped
+pub fn recv_tree<R: Read + ?Sized>(
+    reader: &mut R,
+    target_dir: &Path,
+    compression: Compression,
+    tx: Option<&Sender<u64>>,
+) -> io::Result<TreeTransferred> {
+    let mut done = TreeTransferred::default();
+    let mut decompressor = Decompressor::new(compression)?;
+    // applied when the tree is complete, creating their entries changes
+    // their mtime and needs write permission
+    let mut dirs = vec![];
+    loop {
+        let (path, kind, meta) = match read_message(reader)? {
+            Message::Entry { path, kind, meta } => (path, kind, meta),
+            Message::Skipped(skipped) => {
+                done.skipped.push(skipped);
+                continue;
+            }
+            Message::Eof => break,
+            Message::Error(e) => return Err(io::Error::other(e)),
+            msg => return Err(protocol::unexpected(&msg)),
+        };
+        let relative = checked_path(target_dir, &path)?;
+        let target = target_dir.join(relative);
+        match kind {
+            EntryKind::Dir => {
+                match fs::create_dir(&target) {
+                    // an existing directory is reused, a symlink to one is not
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                        let meta =
+                            fs::symlink_metadata(&target).map_err(|e| entry_error(&target, e))?;
+                        if !meta.is_dir() {
+                            return Err(entry_error(
+                                &target,
+                                io::Error::new(ErrorKind::AlreadyExists, "not a directory"),
+                            ));
+                        }
+                    }
+                    result => result.map_err(|e| entry_error(&target, e))?,
+                }
+                dirs.push((target, meta));
+                done.dirs += 1;
+            }
+            EntryKind::Symlink(link) => {
+                remove_file(&target)?;
+                symlink(link, &target).map_err(|e| entry_error(&target, e))?;
+                done.symlinks += 1;
+            }
+            EntryKind::File => {
+                remove_file(&target)?;
+                // a symlink created meanwhile is not written through
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&target)
+                    .map_err(|e| entry_error(&target, e))?;
+                let mut writer = HashWriter::new(&file);
+                let len = transfer::recv_contents(
+                    reader,
+                    &mut writer,
+                    meta.size,
+                    0,
+                    &mut decompressor,
+                    tx,
+                )?;
+                transfer::verify_digest(reader, &writer.digest())
+                    .and_then(|_| apply_meta(&file, &meta))
+                    .map_err(|e| {
+                        let _ = fs::remove_file(&target);
+                        entry_error(&target, e)
+                    })?;
+                done.files += 1;
+                done.bytes += len;
+            }
+        }
+    }
+    for (dir, meta) in dirs.iter().rev() {
+        // the metadata of a directory replaced by a symlink is not applied
+        // to the target of the link
+        OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_DIRECTORY)
+            .open(dir)
+            .and_then(|file| apply_meta(&file, meta))
+            .map_err(|e| entry_error(dir, e))?;
+    }
+    done.throughput = decompressor.throughput();
+    Ok(done)
+}
+
+/// checks that path stays below target_dir, it must be relative without ..
+/// and must not pass through a symlink. a trailing slash is dropped, it
+/// would follow a symlink at path
+fn checked_path(target_dir: &Path, path: &str) -> io::Result<PathBuf> {
+    let path = Path::new(path);
+    let normal = path.components().all(|c| matches!(c, Component::Normal(_)));
+    if path.as_os_str().is_empty() || !normal {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid path {} in tree", path.display()),
+        ));
+    }
+    for parent in path.ancestors().skip(1) {
+        let parent = target_dir.join(parent);
+        if fs::symlink_metadata(&parent)
+            .map(|m| m.file_type().is_symlink())
+            .unwrap_or(false)
+        {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "path {} passes the symlink {}",
+                    path.display(),
+                    parent.display()
+                ),
+            ));
+        }
+    }
+    Ok(path.components().collect())
+}
+
+/// removes a file or symlink at path, so it is replaced instead of written
+/// through
+fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if !meta.is_dir() => fs::remove_file(path).map_err(|e| entry_error(path, e)),
+        _ => Ok(()),
+    }
+}
+
+fn entry_error(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("trsh-tree-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn dir_entry(path: &str) -> Vec<u8> {
+        let mut stream = vec![];
+        let meta = FileMeta {
+            size: 0,
+            mode: 0o777,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        };
+        let entry = Message::Entry {
+            path: path.to_string(),
+            kind: EntryKind::Dir,
+            meta,
+        };
+        write_message(&mut stream, &entry).unwrap();
+        write_message(&mut stream, &Message::Eof).unwrap();
+        stream
+    }
+
+    #[test]
+    fn symlinked_dir_is_not_written_through() {
+        let dir = scratch("symlink");
+        let victim = dir.join("victim");
+        fs::create_dir(&victim).unwrap();
+        fs::set_permissions(&victim, fs::Permissions::from_mode(0o700)).unwrap();
+        let target_dir = dir.join("target");
+        fs::create_dir(&target_dir).unwrap();
+        symlink(&victim, target_dir.join("tree")).unwrap();
+        for path in &["tree", "tree/"] {
+            let stream = dir_entry(path);
+            let result = recv_tree(&mut &stream[..], &target_dir, Compression::None, None);
+            assert!(result.is_err(), "{} was accepted", path);
+            let mode = fs::metadata(&victim).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o700);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skipped_entries_reach_the_receiver() {
+        let dir = scratch("skipped");
+        let source = dir.join("tree");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file"), b"contents").unwrap();
+        let fifo = source.join("fifo");
+        let c_fifo = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_fifo.as_ptr(), 0o600) }, 0);
+        let target_dir = dir.join("target");
+        fs::create_dir(&target_dir).unwrap();
+
+        let mut stream = vec![];
+        let filter = Filter::new(&[], &[]).unwrap();
+        let sent = send_tree(&mut stream, &source, &filter, Compression::Zstd, None).unwrap();
+        let received = recv_tree(&mut &stream[..], &target_dir, Compression::Zstd, None).unwrap();
+        assert_eq!(sent.skipped.len(), 1);
+        assert!(sent.skipped[0].contains("fifo: not a regular file"));
+        assert_eq!(received.skipped, sent.skipped);
+        assert_eq!(received.files, 1);
+        assert!(!target_dir.join("tree/fifo").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn existing_dir_is_reused() {
+        let dir = scratch("reuse");
+        fs::create_dir(dir.join("tree")).unwrap();
+        let stream = dir_entry("tree/");
+        let done = recv_tree(&mut &stream[..], &dir, Compression::None, None).unwrap();
+        assert_eq!(done.dirs, 1);
+        let mode = fs::metadata(dir.join("tree")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o777);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! terminal modes sent with a Shell request
+//!
+//! the flag bits and control character indices of termios differ between
+//! linux and macos, so the modes are exchanged as <u8 opcode> <u32 value>
+//! pairs with the opcodes of ssh (RFC 4254). a flag is 0 or 1, a control
+//! character is its byte value. opcodes unknown to the receiver are
+//! ignored.
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use libc::termios;
+
+/// control characters
+const CHARS: &[(u8, usize)] = &[
+    (1, libc::VINTR),
+    (2, libc::VQUIT),
+    (3, libc::VERASE),
+    (4, libc::VKILL),
+    (5, libc::VEOF),
+    (6, libc::VEOL),
+    (7, libc::VEOL2),
+    (8, libc::VSTART),
+    (9, libc::VSTOP),
+    (10, libc::VSUSP),
+    (12, libc::VREPRINT),
+    (13, libc::VWERASE),
+    (14, libc::VLNEXT),
+    (18, libc::VDISCARD),
+];
+
+const INPUT_FLAGS: &[(u8, libc::tcflag_t)] = &[
+    (30, libc::IGNPAR),
+    (31, libc::PARMRK),
+    (32, libc::INPCK),
+    (33, libc::ISTRIP),
+    (34, libc::INLCR),
+    (35, libc::IGNCR),
+    (36, libc::ICRNL),
+    (38, libc::IXON),
+    (39, libc::IXANY),
+    (40, libc::IXOFF),
+    (41, libc::IMAXBEL),
+    (42, libc::IUTF8),
+];
+
+const LOCAL_FLAGS: &[(u8, libc::tcflag_t)] = &[
+    (50, libc::ISIG),
+    (51, libc::ICANON),
+    (53, libc::ECHO),
+    (54, libc::ECHOE),
+    (55, libc::ECHOK),
+    (56, libc::ECHONL),
+    (57, libc::NOFLSH),
+    (58, libc::TOSTOP),
+    (59, libc::IEXTEN),
+    (60, libc::ECHOCTL),
+    (61, libc::ECHOKE),
+    (62, libc::PENDIN),
+];
+
+const OUTPUT_FLAGS: &[(u8, libc::tcflag_t)] = &[
+    (70, libc::OPOST),
+    (72, libc::ONLCR),
+    (73, libc::OCRNL),
+    (74, libc::ONOCR),
+    (75, libc::ONLRET),
+];
+
+const CS7: u8 = 90;
+const CS8: u8 = 91;
+const CONTROL_FLAGS: &[(u8, libc::tcflag_t)] = &[(92, libc::PARENB), (93, libc::PARODD)];
+
+/// reads the encoded modes of the terminal fd
+pub fn read_modes(fd: RawFd) -> io::Result<Vec<(u8, u32)>> {
+    let mut t: termios = unsafe { mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(encode(&t))
+}
+
+/// applies encoded modes to the terminal fd
+pub fn apply_modes(fd: RawFd, modes: &[(u8, u32)]) -> io::Result<()> {
+    let mut t: termios = unsafe { mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    apply(&mut t, modes);
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn encode(t: &termios) -> Vec<(u8, u32)> {
+    let mut modes = vec![];
+    for &(op, index) in CHARS {
+        modes.push((op, t.c_cc[index] as u32));
+    }
+    push_flags(&mut modes, INPUT_FLAGS, t.c_iflag);
+    push_flags(&mut modes, LOCAL_FLAGS, t.c_lflag);
+    push_flags(&mut modes, OUTPUT_FLAGS, t.c_oflag);
+    let size = t.c_cflag & libc::CSIZE;
+    modes.push((CS7, (size == libc::CS7) as u32));
+    modes.push((CS8, (size == libc::CS8) as u32));
+    push_flags(&mut modes, CONTROL_FLAGS, t.c_cflag);
+    modes
+}
+
+fn apply(t: &mut termios, modes: &[(u8, u32)]) {
+    for &(op, value) in modes {
+        if let Some(&(_, index)) = CHARS.iter().find(|&&(o, _)| o == op) {
+            t.c_cc[index] = value as libc::cc_t;
+        } else if set_flag(&mut t.c_iflag, INPUT_FLAGS, op, value)
+            || set_flag(&mut t.c_lflag, LOCAL_FLAGS, op, value)
+            || set_flag(&mut t.c_oflag, OUTPUT_FLAGS, op, value)
+            || set_flag(&mut t.c_cflag, CONTROL_FLAGS, op, value)
+        {
+            continue;
+        } else if (op == CS7 || op == CS8) && value != 0 {
+            t.c_cflag &= !libc::CSIZE;
+            t.c_cflag |= if op == CS7 { libc::CS7 } else { libc::CS8 };
+        }
+    }
+}
+
+fn push_flags(modes: &mut Vec<(u8, u32)>, table: &[(u8, libc::tcflag_t)], flags: libc::tcflag_t) {
+    for &(op, bit) in table {
+        modes.push((op, (flags & bit != 0) as u32));
+    }
+}
+
+/// sets or clears the bit of op, returns false when op is not in table
+fn set_flag(
+    flags: &mut libc::tcflag_t,
+    table: &[(u8, libc::tcflag_t)],
+    op: u8,
+    value: u32,
+) -> bool {
+    match table.iter().find(|&&(o, _)| o == op) {
+        Some(&(_, bit)) => {
+            if value != 0 {
+                *flags |= bit;
+            } else {
+                *flags &= !bit;
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_bits(table: &[(u8, libc::tcflag_t)]) -> libc::tcflag_t {
+        table.iter().fold(0, |flags, &(_, bit)| flags | bit)
+    }
+
+    fn assert_same(a: &termios, b: &termios) {
+        for &(op, index) in CHARS {
+            assert_eq!(a.c_cc[index], b.c_cc[index], "control character {}", op);
+        }
+        assert_eq!(a.c_iflag, b.c_iflag);
+        assert_eq!(a.c_lflag, b.c_lflag);
+        assert_eq!(a.c_oflag, b.c_oflag);
+        assert_eq!(a.c_cflag, b.c_cflag);
+    }
+
+    #[test]
+    fn modes_round_trip() {
+        let mut source: termios = unsafe { mem::zeroed() };
+        for &(op, index) in CHARS {
+            source.c_cc[index] = op + 100;
+        }
+        source.c_iflag = libc::ICRNL | libc::IXON | libc::IUTF8;
+        source.c_lflag = libc::ISIG | libc::ICANON | libc::ECHO | libc::IEXTEN;
+        source.c_oflag = libc::OPOST | libc::ONLCR;
+        source.c_cflag = libc::CS8 | libc::PARENB;
+
+        // every flag set, so the cleared ones have to be cleared by apply
+        let mut target: termios = unsafe { mem::zeroed() };
+        target.c_iflag = all_bits(INPUT_FLAGS);
+        target.c_lflag = all_bits(LOCAL_FLAGS);
+        target.c_oflag = all_bits(OUTPUT_FLAGS);
+        target.c_cflag = libc::CS7 | all_bits(CONTROL_FLAGS);
+        apply(&mut target, &encode(&source));
+        assert_same(&target, &source);
+    }
+
+    #[test]
+    fn unknown_opcodes_are_skipped() {
+        let mut t: termios = unsafe { mem::zeroed() };
+        // 11 is VDSUSP and 37 IUCLC, linux has neither
+        apply(&mut t, &[(11, 5), (37, 1), (160, 1), (255, 1)]);
+        assert_same(&t, &unsafe { mem::zeroed() });
+
+        apply(&mut t, &[(200, 1), (53, 1), (11, 5), (1, 3)]);
+        assert_eq!(t.c_lflag, libc::ECHO);
+        assert_eq!(t.c_cc[libc::VINTR], 3);
+    }
+}
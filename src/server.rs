@@ -11,46 +11,89 @@
 //! 
 //! the follwing option are avalaible:
 //! -s <ip:port> - listens on ip / port and wait for backconnects 
+//! -l - keeps listening, every client becomes a numbered session that
+//!      is controlled from the operator console
+//! -t <seconds> - kills the command when it runs longer than seconds, the
+//!                server then exits with 124
+//!
+//! file transfers and command output are compressed when the client supports
+//! it, --compress fails without compression and --no-compress sends them as
+//! they are. a finished transfer reports its compression ratio and speed
 //! 
 //! the following commands are available:
 //! 
-//! <command> - executes the command an returns the result (default is "w")
+//! <command> - executes the command an returns the result (default is "w"),
+//!             the server exits with the exit code of the command. stdin
+//!             is forwarded to the command unless started with -n. with
+//!             -a the command and its arguments are run without a shell,
+//!             -C <dir> sets the working directory and -e <key=value> adds
+//!             environment variables
 //! 
 //! get <source file> <target dir> - transfer a file from the client to the server 
 //! put <source file> <target dir> - transfer a file from the server to the client
+//!                                  with -r get and put transfer a directory tree,
+//!                                  -i <glob> and -x <glob> select its files
 //! 
-//! shell <-r> - start an interactive shell on the client an forward it to server, when 
-//!              started with <-r> the shell is set to raw mode
+//! shell <-c> - start an interactive shell on the client an forward it to server, a
+//!              terminal is set to raw mode unless started with <-c>. the shell program
+//!              and its arguments can follow, -C <dir> sets the start directory
+//!              and -e <key=value> adds environment variables
 //!  
 //! the keys for encryption are set in build.rs 
-use io::BufReader;
+use io::{Read, Write};
+use std::collections::VecDeque;
+use std::env;
 use std::io;
 use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
 use std::os::unix::io::RawFd;
-use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::path;
-use std::process::exit;
-use std::{
-    fs::File,
-    io::prelude::*,
-    sync::mpsc::{self, Receiver, Sender},
-};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use clap::{App, Arg, SubCommand};
+use signal_hook::consts::SIGWINCH;
+use signal_hook::iterator::Signals;
 use terminal_size::{terminal_size, Height, Width};
-use termios::*;
 
-use cryptolib::cryptolib_salsa::Crypto;
+use cryptolib::compress::Decompressor;
+use cryptolib::cryptolib_handshake;
+use cryptolib::mux::{Channel, Mux};
+use cryptolib::protocol::{self, read_message, write_message, Compression, Message};
+use cryptolib::transfer;
+use cryptolib::tree;
+use cryptolib::tty_modes;
+
+mod console;
+mod session;
+mod terminal;
+use session::Sessions;
+use terminal::RawMode;
 
 // AES const KEY: &'static [u8; 16] = b"Fahm9Oruet8zahco";
 // AES const IV: &'static [u8; 16] = b"biTh0eoYbiTh0eoY";
 //const KEY: &'static [u8; 32] = b"Fahm9Oruet8zahcoFahm9Oruet8zahco";
 //const IV: &'static [u8; 8] = b"biTh0eoY";
 //const ID: &'static str = "ohpie2naiwoo1lah6aeteexi5beiRas7";
-const ID: &'static str = env!("TRSH_ID");
 
-const KEY: &'static [u8] = env!("TRSH_KEY").as_bytes();
-const IV: &'static [u8] = env!("TRSH_IV").as_bytes();
+/// static key authenticating the key exchange, the session keys are negotiated per connection
+const KEY: &[u8] = env!("TRSH_KEY").as_bytes();
+
+/// time a client gets to complete the authentication
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// first and longest pause after a failed accept, like running out of file
+/// descriptors, the pause doubles while accept keeps failing
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// exit code of the server when the remote command could not be run
+const EXIT_ERROR: i32 = 255;
+/// exit code of the server when the remote command timed out, like timeout(1)
+const EXIT_TIMEOUT: i32 = 124;
 
 /// starting point of the server
 fn main() {
@@ -77,17 +120,143 @@ fn main() {
                 .required(false)
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("no_stdin")
+                .long("no_stdin")
+                .short("n")
+                .help("does not forward stdin to the command")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .short("t")
+                .value_name("SECONDS")
+                .help("kills the command when it runs longer than SECONDS")
+                .required(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("argv")
+                .long("argv")
+                .short("a")
+                .help("runs COMMAND and its arguments without a shell")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("redirect_stderr"),
+        )
+        .arg(
+            Arg::with_name("cwd")
+                .long("cwd")
+                .short("C")
+                .value_name("DIR")
+                .help("runs the command in DIR")
+                .required(false)
+                .takes_value(true)
+                .requires("argv"),
+        )
+        .arg(
+            Arg::with_name("env")
+                .long("env")
+                .short("e")
+                .value_name("KEY=VALUE")
+                .help("sets an environment variable of the command")
+                .required(false)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_env)
+                .requires("argv"),
+        )
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .short("l")
+                .help("keeps listening and manages the clients as sessions")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .long("compress")
+                .short("z")
+                .help("compresses the command output, fails when the client can not")
+                .required(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no_compress")
+                .long("no-compress")
+                .short("Z")
+                .help("sends the command output uncompressed")
+                .required(false)
+                .takes_value(false)
+                .conflicts_with("compress"),
+        )
         .arg(
             Arg::with_name("COMMAND")
                 .help("command to execute")
                 .required(false)
                 .default_value("w")
                 .takes_value(true)
+                .multiple(true)
                 .index(1),
         )
         .subcommand(
             SubCommand::with_name("get")
                 .help("get a file")
+                .arg(
+                    Arg::with_name("recursive")
+                        .long("recursive")
+                        .short("r")
+                        .help("gets a directory tree, symlinks are kept as links")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .short("i")
+                        .value_name("GLOB")
+                        .help("transfers only the files of the tree matching GLOB")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(validate_glob)
+                        .requires("recursive"),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .short("x")
+                        .value_name("GLOB")
+                        .help("skips the files and directories of the tree matching GLOB")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(validate_glob)
+                        .requires("recursive"),
+                )
+                .arg(
+                    Arg::with_name("compress")
+                        .long("compress")
+                        .short("z")
+                        .help("compresses the file contents, fails when the client can not")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("no_compress")
+                        .long("no-compress")
+                        .short("Z")
+                        .help("sends the file contents uncompressed")
+                        .required(false)
+                        .takes_value(false)
+                        .conflicts_with("compress"),
+                )
                 .arg(
                     Arg::with_name("SOURCE_FILE")
                         .required(true)
@@ -104,6 +273,57 @@ fn main() {
         .subcommand(
             SubCommand::with_name("put")
                 .help("put a file")
+                .arg(
+                    Arg::with_name("recursive")
+                        .long("recursive")
+                        .short("r")
+                        .help("puts a directory tree, symlinks are kept as links")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .short("i")
+                        .value_name("GLOB")
+                        .help("transfers only the files of the tree matching GLOB")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(validate_glob)
+                        .requires("recursive"),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .short("x")
+                        .value_name("GLOB")
+                        .help("skips the files and directories of the tree matching GLOB")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(validate_glob)
+                        .requires("recursive"),
+                )
+                .arg(
+                    Arg::with_name("compress")
+                        .long("compress")
+                        .short("z")
+                        .help("compresses the file contents, fails when the client can not")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("no_compress")
+                        .long("no-compress")
+                        .short("Z")
+                        .help("sends the file contents uncompressed")
+                        .required(false)
+                        .takes_value(false)
+                        .conflicts_with("compress"),
+                )
                 .arg(
                     Arg::with_name("SOURCE_FILE")
                         .required(true)
@@ -118,15 +338,57 @@ fn main() {
                 ),
         )
         .subcommand(
-            SubCommand::with_name("shell").help("allocate shell").arg(
-                Arg::with_name("raw_mode")
-                    .long("raw")
-                    .short("r")
-                    .value_name("RAW")
-                    .help("sets terminal into raw mode")
-                    .required(false)
-                    .takes_value(false),
-            ),
+            SubCommand::with_name("shell")
+                .help("allocate shell")
+                .arg(
+                    Arg::with_name("raw_mode")
+                        .long("raw")
+                        .short("r")
+                        .value_name("RAW")
+                        .help("sets terminal into raw mode, the default on a terminal")
+                        .required(false)
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("cooked_mode")
+                        .long("cooked")
+                        .short("c")
+                        .help("leaves the terminal in cooked mode")
+                        .required(false)
+                        .conflicts_with("raw_mode")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("cwd")
+                        .long("cwd")
+                        .short("C")
+                        .value_name("DIR")
+                        .help("starts the shell in DIR")
+                        .required(false)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("env")
+                        .long("env")
+                        .short("e")
+                        .value_name("KEY=VALUE")
+                        .help("sets an environment variable of the shell, like TERM or LANG")
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(validate_env),
+                )
+                .arg(
+                    Arg::with_name("PROGRAM")
+                        .help(
+                            "shell and its arguments, the client falls back to $SHELL, bash and sh",
+                        )
+                        .required(false)
+                        .takes_value(true)
+                        .multiple(true)
+                        .index(1),
+                ),
         )
         .get_matches();
 
@@ -135,233 +397,992 @@ fn main() {
         .parse::<SocketAddr>()
         .unwrap_or_else(|e| panic!(r#"--server_addr value "{}" invalid: {}"#, server_addr, e));
 
+    let timeout = flags.value_of("timeout").map(|timeout| {
+        parse_timeout(timeout).unwrap_or_else(|| panic!(r#"--timeout value "{}" invalid"#, timeout))
+    });
+
+    if let Err(e) = terminal::restore_on_exit() {
+        eprintln!("could not watch the termination signals: {}", e);
+    }
+
     let listener = TcpListener::bind(server_addr).unwrap();
 
-    let (stream, addr) = listener.accept().expect("no connection");
-    println!("Connection from {}", addr);
+    if flags.is_present("listen") {
+        let sessions = Arc::new(Sessions::default());
+        let accepted = sessions.clone();
+        thread::spawn(move || accept_sessions(listener, accepted));
+        console::run_console(&sessions, timeout);
+        return;
+    }
+
+    let (mux, compression) = loop {
+        let (stream, addr) = listener.accept().expect("no connection");
+        eprintln!("Connection from {}", addr);
+        match authenticate_client(&stream) {
+            Ok((mux, _, compression)) => break (mux, compression),
+            Err(e) => eprintln!("dropping connection from {}: {}", addr, e),
+        }
+    };
     drop(listener);
-    handle_connection(stream, flags);
+    let code = handle_connection(&mux, compression, flags, timeout);
+    drop(mux);
+    process::exit(code);
+}
+
+/// registers every client authenticating on listener as a session
+fn accept_sessions(listener: TcpListener, sessions: Arc<Sessions>) {
+    let mut backoff = ACCEPT_BACKOFF;
+    loop {
+        let (stream, addr) = match listener.accept() {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("accept failed: {}, retrying in {:?}", e, backoff);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+        backoff = ACCEPT_BACKOFF;
+        eprintln!("Connection from {}", addr);
+        let sessions = sessions.clone();
+        // a slow client must not block the others
+        thread::spawn(move || match authenticate_client(&stream) {
+            Ok((mux, remote_id, compression)) => {
+                let number = sessions.add(addr, remote_id.clone(), compression, mux);
+                eprintln!("session {} opened: {} {}", number, addr, remote_id);
+            }
+            Err(e) => eprintln!("dropping connection from {}: {}", addr, e),
+        });
+    }
 }
 
-fn handle_connection(mut stream: TcpStream, flags: clap::ArgMatches) {
-    let command = flags.value_of("COMMAND").unwrap();
+/// runs the command of the command line, returns the exit code of the server.
+/// compression is the best compression the client supports
+fn handle_connection(
+    mux: &Mux,
+    compression: Compression,
+    flags: clap::ArgMatches,
+    timeout: Option<Duration>,
+) -> i32 {
+    let mut code = 0;
+    let argv: Vec<String> = flags
+        .values_of("COMMAND")
+        .unwrap()
+        .map(|arg| arg.to_string())
+        .collect();
+    let command = argv.join(" ");
     let redirect: &str = " 2>&1";
-    let mut scommand;
-    if flags.is_present("redirect_stderr") {
-        scommand = format!("{}{}", command, redirect);
+    let scommand = if flags.is_present("redirect_stderr") {
+        format!("{}{}", command, redirect)
     } else {
-        scommand = format!("{}", command);
-    }
+        command
+    };
 
     if let Some(flags) = flags.subcommand_matches("get") {
         if flags.is_present("SOURCE_FILE") && flags.is_present("TARGET_DIR") {
-            println!(
+            eprintln!(
                 "GET --> {} to {}",
                 flags.value_of("SOURCE_FILE").unwrap(),
                 flags.value_of("TARGET_DIR").unwrap()
             );
-            scommand = format!("{}|{}", "GET", flags.value_of("SOURCE_FILE").unwrap());
-            send_remote_command(&mut stream, &mut scommand);
-            handle_get_command(
-                stream,
-                flags.value_of("SOURCE_FILE").unwrap(),
-                flags.value_of("TARGET_DIR").unwrap(),
-            );
+            let compression = match request_compression(compress_flag(flags), compression) {
+                Ok(compression) => compression,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return EXIT_ERROR;
+                }
+            };
+            let path = flags.value_of("SOURCE_FILE").unwrap().to_string();
+            let recursive = flags.is_present("recursive");
+            let request = if recursive {
+                Message::GetTree {
+                    path,
+                    include: patterns(flags.values_of("include")),
+                    exclude: patterns(flags.values_of("exclude")),
+                    compression,
+                }
+            } else {
+                Message::Get { path, compression }
+            };
+            let mut channel = match send_remote_command(mux, &request) {
+                Ok(channel) => channel,
+                Err(e) => {
+                    eprintln!("error sending request: {}", e);
+                    return EXIT_ERROR;
+                }
+            };
+            code = if recursive {
+                handle_get_tree_command(
+                    &mut channel,
+                    flags.value_of("TARGET_DIR").unwrap(),
+                    compression,
+                )
+            } else {
+                handle_get_command(
+                    &mut channel,
+                    flags.value_of("SOURCE_FILE").unwrap(),
+                    flags.value_of("TARGET_DIR").unwrap(),
+                    compression,
+                )
+            };
         }
     } else if let Some(flags) = flags.subcommand_matches("put") {
         if flags.is_present("SOURCE_FILE") && flags.is_present("TARGET_DIR") {
-            println!(
+            eprintln!(
                 "PUT --> {} to {}",
                 flags.value_of("SOURCE_FILE").unwrap(),
                 flags.value_of("TARGET_DIR").unwrap()
             );
+            let compression = match request_compression(compress_flag(flags), compression) {
+                Ok(compression) => compression,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return EXIT_ERROR;
+                }
+            };
             let source_file = path::Path::new(flags.value_of("SOURCE_FILE").unwrap());
-            let filename = source_file.file_name().unwrap();
-            scommand = format!(
-                "{}|{}|{}",
-                "PUT",
-                filename.to_str().unwrap(),
-                flags.value_of("TARGET_DIR").unwrap()
-            );
-            send_remote_command(&mut stream, &mut scommand);
-            handle_put_command(
-                stream,
-                flags.value_of("SOURCE_FILE").unwrap(),
-                flags.value_of("TARGET_DIR").unwrap(),
-            );
+            let target_dir = flags.value_of("TARGET_DIR").unwrap().to_string();
+            let recursive = flags.is_present("recursive");
+            let request = if recursive {
+                Message::PutTree {
+                    target_dir,
+                    compression,
+                }
+            } else {
+                let filename = source_file.file_name().unwrap();
+                Message::Put {
+                    name: filename.to_string_lossy().to_string(),
+                    target_dir,
+                    compression,
+                }
+            };
+            let mut channel = match send_remote_command(mux, &request) {
+                Ok(channel) => channel,
+                Err(e) => {
+                    eprintln!("error sending request: {}", e);
+                    return EXIT_ERROR;
+                }
+            };
+            code = if recursive {
+                handle_put_tree_command(
+                    &mut channel,
+                    flags.value_of("SOURCE_FILE").unwrap(),
+                    &patterns(flags.values_of("include")),
+                    &patterns(flags.values_of("exclude")),
+                    compression,
+                )
+            } else {
+                handle_put_command(
+                    &mut channel,
+                    flags.value_of("SOURCE_FILE").unwrap(),
+                    flags.value_of("TARGET_DIR").unwrap(),
+                    compression,
+                )
+            };
         }
     } else if let Some(flags) = flags.subcommand_matches("shell") {
-        if let Some((Width(w), Height(h))) = terminal_size() {
-            scommand = format!("{}|{}|{}", "SHELL", w, h);
-        } else {
-            scommand = format!("{}|{}|{}", "SHELL", 80, 20);
-        }
-        send_remote_command(&mut stream, &mut scommand);
-        if flags.is_present("raw_mode") {
-            run_shell(stream, true);
+        let mut program = flags
+            .values_of("PROGRAM")
+            .into_iter()
+            .flatten()
+            .map(|arg| arg.to_string());
+        let request = shell_request(
+            program.next(),
+            program.collect(),
+            env_vars(flags.values_of("env")),
+            flags.value_of("cwd").map(|cwd| cwd.to_string()),
+        );
+        let channel = match send_remote_command(mux, &request) {
+            Ok(channel) => channel,
+            Err(e) => {
+                eprintln!("error sending request: {}", e);
+                return EXIT_ERROR;
+            }
+        };
+        code = run_shell(channel, flags.is_present("cooked_mode"));
+    } else {
+        let compression = match request_compression(compress_flag(&flags), compression) {
+            Ok(compression) => compression,
+            Err(e) => {
+                eprintln!("{}", e);
+                return EXIT_ERROR;
+            }
+        };
+        let request = if flags.is_present("argv") {
+            Message::ExecArgv {
+                argv,
+                cwd: flags.value_of("cwd").map(|cwd| cwd.to_string()),
+                env: env_vars(flags.values_of("env")),
+                compression,
+            }
         } else {
-            run_shell(stream, false)
-        }
+            Message::Exec {
+                command: scommand,
+                compression,
+            }
+        };
+        let mut channel = match send_remote_command(mux, &request) {
+            Ok(channel) => channel,
+            Err(e) => {
+                eprintln!("error sending request: {}", e);
+                return EXIT_ERROR;
+            }
+        };
+        code = handle_os_command(
+            &mut channel,
+            !flags.is_present("no_stdin"),
+            timeout,
+            compression,
+        );
+    }
+    // the client keeps the session open until it is closed
+    let _ = send_remote_command(mux, &Message::Close);
+    code
+}
+
+/// runs the key exchange and the mutual challenge-response with a new client
+/// and starts the mux on the encrypted channel, the id in the clients Hello is only informational.
+/// returns the mux, the id and the best compression of the client
+fn authenticate_client(mut stream: &TcpStream) -> io::Result<(Mux, String, Compression)> {
+    stream.set_read_timeout(Some(AUTH_TIMEOUT))?;
+    let keys = cryptolib_handshake::server_handshake(&mut stream, KEY)?;
+    let (writer, mut reader) = keys.open_channel(stream)?;
+
+    let remote_id = match read_message(&mut reader)? {
+        Message::Hello { id } => id,
+        msg => return Err(protocol::unexpected(&msg)),
+    };
+    stream.set_read_timeout(None)?;
+    let compression = Compression::negotiate(keys.caps);
+    eprintln!(
+        "Remote ID: {} (protocol version {}, capabilities {:#x}, compression {})",
+        remote_id,
+        protocol::PROTOCOL_VERSION,
+        keys.caps,
+        compression
+    );
+
+    let mux = Mux::server(writer, reader)?;
+    Ok((mux, remote_id, compression))
+}
+
+/// opens a channel for request and sends it, the reply arrives on the channel
+fn send_remote_command(mux: &Mux, request: &Message) -> io::Result<Channel> {
+    eprintln!("Request {}", request.name());
+    let mut channel = mux.open()?;
+    write_message(&mut channel, request)?;
+    Ok(channel)
+}
+
+fn validate_env(var: String) -> Result<(), String> {
+    match var.split_once('=') {
+        Some((key, _)) if !key.is_empty() => Ok(()),
+        _ => Err(format!(r#""{}" is not KEY=VALUE"#, var)),
+    }
+}
+
+/// splits the KEY=VALUE arguments of --env
+fn env_vars(values: Option<clap::Values>) -> Vec<(String, String)> {
+    values
+        .into_iter()
+        .flatten()
+        .filter_map(|var| var.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+fn patterns(values: Option<clap::Values>) -> Vec<String> {
+    values
+        .into_iter()
+        .flatten()
+        .map(|p| p.to_string())
+        .collect()
+}
+
+/// --compress and --no-compress of flags
+fn compress_flag(flags: &clap::ArgMatches) -> Option<bool> {
+    if flags.is_present("compress") {
+        Some(true)
+    } else if flags.is_present("no_compress") {
+        Some(false)
     } else {
-        send_remote_command(&mut stream, &mut scommand);
-        handle_os_command(stream);
+        None
     }
 }
 
-fn send_remote_command(mut stream: &TcpStream, command: &str) {
-    let mut cr = Crypto::new(KEY, IV).unwrap();
-    let mut buffer = [0; 1024];
+/// compression of a request, the best one of the session unless compress
+/// overrides it
+fn request_compression(compress: Option<bool>, session: Compression) -> io::Result<Compression> {
+    match compress {
+        Some(false) => Ok(Compression::None),
+        Some(true) if session == Compression::None => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "the client does not support compression",
+        )),
+        _ => Ok(session),
+    }
+}
 
-    let bytes_read = stream.read(&mut buffer).unwrap();
-    cr.read(&mut buffer[..bytes_read]).unwrap();
+fn validate_glob(glob: String) -> Result<(), String> {
+    globset::Glob::new(&glob)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
 
-    let remote_id = String::from_utf8_lossy(&cr.buffer()[..bytes_read]);
+/// parses a timeout given in seconds
+fn parse_timeout(timeout: &str) -> Option<Duration> {
+    match timeout.parse::<u64>() {
+        Ok(seconds) if seconds > 0 => Some(Duration::from_secs(seconds)),
+        _ => None,
+    }
+}
+
+/// writes the stdout and stderr of a remote command to the own stdout and stderr,
+/// returns its exit code or 128 + the signal number like a shell. with stdin the
+/// own stdin is forwarded to the command, otherwise the command reads an empty input.
+/// a command running longer than timeout is killed and EXIT_TIMEOUT is returned.
+/// the output arrives with compression
+fn handle_os_command(
+    channel: &mut Channel,
+    stdin: bool,
+    timeout: Option<Duration>,
+    compression: Compression,
+) -> i32 {
+    if stdin {
+        let mut writer = channel.clone();
+        thread::spawn(move || {
+            match protocol::send_stream(&mut io::stdin(), &mut writer, None) {
+                // the command ended without reading all of stdin
+                Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+                Err(e) => eprintln!("error forwarding stdin: {}", e),
+                Ok(_) => {}
+            }
+        });
+    } else if let Err(e) = write_message(channel, &Message::Eof) {
+        eprintln!("error sending request: {}", e);
+        return EXIT_ERROR;
+    }
 
-    println!("Remote ID: {}", remote_id);
+    // the timer is cancelled by dropping done once the command ended
+    let (done, cancelled) = mpsc::channel::<()>();
+    let timed_out = Arc::new(AtomicBool::new(false));
+    if let Some(timeout) = timeout {
+        let mut killer = channel.clone();
+        let expired = timed_out.clone();
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = cancelled.recv_timeout(timeout) {
+                expired.store(true, Ordering::SeqCst);
+                eprintln!(
+                    "timeout of {}s reached, killing the command",
+                    timeout.as_secs()
+                );
+                if let Err(e) = write_message(&mut killer, &Message::Kill) {
+                    eprintln!("error sending kill: {}", e);
+                }
+            }
+        });
+    }
 
-    if remote_id != ID {
-        println!("ID not valid remote[{}] <--> [{}]", remote_id, ID);
-        exit(1);
+    let code = read_output(channel, compression);
+    drop(done);
+    if timed_out.load(Ordering::SeqCst) {
+        eprintln!("command timed out");
+        return EXIT_TIMEOUT;
     }
+    code
+}
 
-    cr.write(command.as_bytes()).unwrap();
+/// reads the output of a remote command until its exit status, stdout and
+/// stderr are decompressed separately
+fn read_output(channel: &mut Channel, compression: Compression) -> i32 {
+    let decompressors = Decompressor::new(compression)
+        .and_then(|stdout| Ok((stdout, Decompressor::new(compression)?)));
+    let (mut stdout, mut stderr) = match decompressors {
+        Ok(decompressors) => decompressors,
+        Err(e) => {
+            eprintln!("error reading output: {}", e);
+            return EXIT_ERROR;
+        }
+    };
+    loop {
+        let result = match read_message(channel) {
+            Ok(Message::Data(data)) => stdout
+                .decompress(&data)
+                .and_then(|data| write_flush(&mut io::stdout(), &data)),
+            Ok(Message::Stderr(data)) => stderr
+                .decompress(&data)
+                .and_then(|data| write_flush(&mut io::stderr(), &data)),
+            Ok(Message::ExitStatus(code)) => {
+                print_output_throughput(&stdout, &stderr);
+                eprintln!("exit status {}", code);
+                return code;
+            }
+            Ok(Message::ExitSignal(signal)) => {
+                print_output_throughput(&stdout, &stderr);
+                eprintln!("killed by signal {}", signal);
+                return 128 + signal;
+            }
+            Ok(Message::Error(e)) => {
+                eprintln!("remote error: {}", e);
+                return EXIT_ERROR;
+            }
+            Ok(msg) => Err(protocol::unexpected(&msg)),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("error reading output: {}", e);
+            return EXIT_ERROR;
+        }
+    }
+}
 
-    println!("Len CR Buffer {}", cr.buffer().len());
-    stream.write(cr.buffer()).unwrap();
-    stream.flush().unwrap();
+/// prints the compression ratio of a compressed command output. there is no
+/// speed, the output arrives as fast as the command writes it
+fn print_output_throughput(stdout: &Decompressor, stderr: &Decompressor) {
+    let mut throughput = stdout.throughput();
+    throughput.add(&stderr.throughput());
+    if throughput.compression != Compression::None {
+        eprintln!(
+            "{} bytes of output, {} compressed to {} bytes, ratio {:.2}",
+            throughput.bytes,
+            throughput.compression,
+            throughput.wire_bytes,
+            throughput.ratio()
+        );
+    }
 }
 
-fn handle_os_command(mut stream: TcpStream) {
-    let mut cr = Crypto::new(KEY, IV).unwrap();
-    let stdout = io::stdout();
-    let mut handle = stdout.lock();
-    cr.copy(&mut stream, &mut handle).unwrap();
+fn write_flush<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    writer.write_all(data)?;
+    writer.flush()
 }
 
-fn handle_get_command(mut stream: TcpStream, source_file: &str, target_dir: &str) {
-    println!("GET {}", source_file);
+/// receives source_file of the client into target_dir, returns the exit code
+/// of the server
+fn handle_get_command(
+    channel: &mut Channel,
+    source_file: &str,
+    target_dir: &str,
+    compression: Compression,
+) -> i32 {
+    eprintln!("GET {}", source_file);
     let source_path = path::Path::new(source_file);
-    let filename = source_path.file_name().unwrap();
+    let filename = match source_path.file_name() {
+        Some(filename) => filename,
+        None => {
+            eprintln!("invalid source file {}", source_file);
+            return EXIT_ERROR;
+        }
+    };
     let target_path = path::Path::new(target_dir).join(filename);
 
-    let mut cr = Crypto::new(KEY, IV).unwrap();
-    let mut output = match File::create(target_path) {
-        Ok(output) => output,
+    let (tx, rx): (Sender<u64>, Receiver<u64>) = mpsc::channel();
+    let progress = print_progress(rx);
+    let result = transfer::recv_file(channel, &target_path, compression, Some(&tx));
+    drop(tx);
+    let _ = progress.join();
+    match result {
+        Ok(done) => {
+            eprintln!("{}", done);
+            0
+        }
         Err(e) => {
-            println!("could not create file: {}", e);
-            return;
+            eprintln!("error receiving {}: {}", target_path.display(), e);
+            EXIT_ERROR
         }
-    };
-    //let (tx, rx) = channel();
+    }
+}
+
+/// sends source_file to target_dir of the client, returns the exit code of
+/// the server
+fn handle_put_command(
+    channel: &mut Channel,
+    source_file: &str,
+    target_dir: &str,
+    compression: Compression,
+) -> i32 {
+    eprintln!("PUT {} to {}", source_file, target_dir);
     let (tx, rx): (Sender<u64>, Receiver<u64>) = mpsc::channel();
+    let progress = print_progress(rx);
+    let source_path = path::Path::new(source_file);
+    let result = transfer::send_file(channel, source_path, compression, Some(&tx));
+    drop(tx);
+    let _ = progress.join();
+    let done = match result {
+        Ok(done) => done,
+        Err(e) => {
+            eprintln!("error sending {}: {}", source_file, e);
+            return EXIT_ERROR;
+        }
+    };
+    match read_message(channel) {
+        Ok(Message::Eof) => {
+            eprintln!("{}", done);
+            0
+        }
+        Ok(Message::Error(e)) => {
+            eprintln!("remote error: {}", e);
+            EXIT_ERROR
+        }
+        Ok(msg) => {
+            eprintln!("error: {}", protocol::unexpected(&msg));
+            EXIT_ERROR
+        }
+        Err(e) => {
+            eprintln!("error reading reply: {}", e);
+            EXIT_ERROR
+        }
+    }
+}
 
-    let mut counter = 0;
-    ::std::thread::spawn(move || loop {
-        counter = match rx.recv() {
-            Ok(counter) => counter,
-            Err(_e) => {
-                break;
+/// receives a directory tree of the client into target_dir, returns the exit
+/// code of the server
+fn handle_get_tree_command(
+    channel: &mut Channel,
+    target_dir: &str,
+    compression: Compression,
+) -> i32 {
+    eprintln!("GET tree to {}", target_dir);
+    let (tx, rx): (Sender<u64>, Receiver<u64>) = mpsc::channel();
+    let progress = print_progress(rx);
+    let target_path = path::Path::new(target_dir);
+    let result = tree::recv_tree(channel, target_path, compression, Some(&tx));
+    drop(tx);
+    let _ = progress.join();
+    match result {
+        Ok(done) => {
+            for skipped in &done.skipped {
+                eprintln!("skipped {}", skipped);
             }
-        };
-        if counter == 0 {
-            break;
+            eprintln!("{}", done);
+            0
         }
-        println!("Transferred: {}\r", counter);
-    });
-
-    match cr.copy_buf(&mut stream, &mut output, &tx) {
-        Ok(cnt) => println!("{} bytes were transferred", cnt),
         Err(e) => {
-            println!("error copying data: {}", e);
-            return;
+            eprintln!("error receiving tree: {}", e);
+            EXIT_ERROR
         }
-    };
-    tx.send(0).unwrap();
-    drop(output);
+    }
 }
 
-fn handle_put_command(mut stream: TcpStream, source_file: &str, target_dir: &str) {
-    println!("PUT {} to {}", source_file, target_dir);
-    let mut cr = Crypto::new(KEY, IV).unwrap();
-    let input = match File::open(source_file) {
-        Ok(input) => input,
+/// sends the directory tree at source to the client, with the files
+/// selected by the include and exclude globs. returns the exit code of the
+/// server
+fn handle_put_tree_command(
+    channel: &mut Channel,
+    source: &str,
+    include: &[String],
+    exclude: &[String],
+    compression: Compression,
+) -> i32 {
+    eprintln!("PUT tree {}", source);
+    let filter = match tree::Filter::new(include, exclude) {
+        Ok(filter) => filter,
         Err(e) => {
-            println!("could not open source file: {}", e);
-            return;
+            eprintln!("invalid pattern: {}", e);
+            let _ = write_message(channel, &Message::Error(format!("invalid pattern: {}", e)));
+            return EXIT_ERROR;
         }
     };
-    let mut bufreader = BufReader::new(input);
     let (tx, rx): (Sender<u64>, Receiver<u64>) = mpsc::channel();
-    let mut counter = 0;
-    ::std::thread::spawn(move || loop {
-        counter = match rx.recv() {
-            Ok(counter) => counter,
-            Err(_e) => {
-                break;
+    let progress = print_progress(rx);
+    let source_path = path::Path::new(source);
+    let result = tree::send_tree(channel, source_path, &filter, compression, Some(&tx));
+    drop(tx);
+    let _ = progress.join();
+    let done = match result {
+        Ok(done) => done,
+        Err(e) => {
+            eprintln!("error sending {}: {}", source, e);
+            if e.kind() == io::ErrorKind::BrokenPipe {
+                // the client stopped at an entry it could not write, it says why
+                if let Ok(Message::Error(e)) = read_message(channel) {
+                    eprintln!("remote error: {}", e);
+                }
+            } else {
+                let _ = write_message(channel, &Message::Error(e.to_string()));
             }
-        };
-        if counter == 0 {
-            break;
+            return EXIT_ERROR;
+        }
+    };
+    for skipped in &done.skipped {
+        eprintln!("skipped {}", skipped);
+    }
+    match read_message(channel) {
+        Ok(Message::Eof) => {
+            eprintln!("{}", done);
+            0
+        }
+        Ok(Message::Error(e)) => {
+            eprintln!("remote error: {}", e);
+            EXIT_ERROR
+        }
+        Ok(msg) => {
+            eprintln!("error: {}", protocol::unexpected(&msg));
+            EXIT_ERROR
         }
-        println!("Transferred: {}\r", counter);
-    });
-    match cr.copy_buf(&mut bufreader, &mut stream, &tx) {
-        Ok(cnt) => println!("{} bytes were transferred", cnt),
         Err(e) => {
-            println!("error copying data: {}", e);
-            return;
+            eprintln!("error reading reply: {}", e);
+            EXIT_ERROR
         }
+    }
+}
+
+/// prints the progress of a transfer until the sender is dropped
+fn print_progress(rx: Receiver<u64>) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        while let Ok(counter) = rx.recv() {
+            eprintln!("Transferred: {}\r", counter);
+        }
+    })
+}
+
+/// requests a shell with the size, TERM and modes of the local terminal,
+/// without program the client picks its shell
+fn shell_request(
+    program: Option<String>,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    cwd: Option<String>,
+) -> Message {
+    let (cols, rows) = match terminal_size() {
+        Some((Width(w), Height(h))) => (w, h),
+        None => (80, 20),
     };
-    tx.send(0).unwrap();
-    drop(bufreader);
+    // read before run_shell switches to raw mode, the remote pty gets the sane modes
+    let modes = tty_modes::read_modes(io::stdin().as_raw_fd()).unwrap_or_default();
+    Message::Shell {
+        cols,
+        rows,
+        program,
+        args,
+        env,
+        cwd,
+        term: env::var("TERM").ok(),
+        modes,
+    }
 }
 
-fn run_shell(mut s: TcpStream, raw: bool) {
+/// forwards the local terminal to a remote shell until the shell exits or
+/// the operator detaches, returns the exit code of the shell like
+/// handle_os_command. a terminal is switched to raw mode unless cooked is set
+fn run_shell(channel: Channel, cooked: bool) -> i32 {
     let l_stdin = io::stdin().as_raw_fd();
-    let mut sane_termios: Termios = Termios::from_fd(l_stdin).unwrap();
-    if raw {
-        sane_termios = setup_raw(l_stdin).unwrap();
+    let mut raw_mode = None;
+    if !cooked && terminal::is_terminal(l_stdin) {
+        match RawMode::enable(l_stdin) {
+            Ok(mode) => raw_mode = Some(Arc::new(mode)),
+            Err(e) => eprintln!("could not set raw mode: {}", e),
+        }
     }
-    let mut f_stdin = unsafe { File::from_raw_fd(l_stdin) };
-    let mut f_stdout = unsafe { File::from_raw_fd(io::stdout().as_raw_fd()) };
+    eprint!("press ctrl-] to detach, ~? at the start of a line lists the escapes\r\n");
 
-    println!("created local fds");
+    let stop = Arc::new(AtomicBool::new(false));
+    let disconnected = Arc::new(AtomicBool::new(false));
+    let stats = Arc::new(ShellStats {
+        channel: channel.id(),
+        started: Instant::now(),
+        sent: AtomicU64::new(0),
+        received: AtomicU64::new(0),
+    });
+    let mut input = ShellInput {
+        fd: l_stdin,
+        stop: stop.clone(),
+        detached: false,
+        control: channel.clone(),
+        disconnected: disconnected.clone(),
+        raw_mode: raw_mode.clone(),
+        stats: stats.clone(),
+        line_start: true,
+        escape: false,
+        pending: VecDeque::new(),
+    };
+
+    // the remote pty follows the size of the local terminal
+    let resize = match Signals::new([SIGWINCH]) {
+        Ok(signals) => {
+            let handle = signals.handle();
+            let mut writer = channel.clone();
+            let thread = thread::spawn(move || forward_resizes(signals, &mut writer));
+            Some((handle, thread))
+        }
+        Err(e) => {
+            eprint!("could not watch the terminal size: {}\r\n", e);
+            None
+        }
+    };
+
+    let mut writer = channel.clone();
+    let mut reader = channel;
+    let input_thread = thread::spawn(move || {
+        if let Err(e) = protocol::send_stream(&mut input, &mut writer, None) {
+            // ~. closed the channel
+            if !input.disconnected.load(Ordering::SeqCst) {
+                eprintln!("Error copy: {}", e);
+            }
+        }
+        // the end of the input hangs up the shell, one ignoring the hangup
+        // never ends its output. close the channel like ~. after a while
+        if input.detached && !input.disconnected.load(Ordering::SeqCst) && !input.wait_stop() {
+            print_flush("the shell ignored the hangup, closing the channel\r\n");
+            input.disconnected.store(true, Ordering::SeqCst);
+            input.control.close();
+        }
+    });
+    // the output ends with Eof followed by the exit status of the shell
+    let output_thread = thread::spawn(move || {
+        let mut output = ShellOutput(stats);
+        protocol::recv_stream(&mut reader, &mut output, None)?;
+        read_message(&mut reader)
+    });
+
+    let status = output_thread
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("output thread panicked")));
+    stop.store(true, Ordering::SeqCst);
+    let _ = input_thread.join();
+    if let Some((handle, thread)) = resize {
+        handle.close();
+        let _ = thread.join();
+    }
+    // the input thread dropped its reference, this restores the terminal
+    drop(raw_mode);
 
-    let mut in_stream = s.try_clone().unwrap();
-    ::std::thread::spawn(move || copyio(&mut f_stdin, &mut in_stream));
-    let child = ::std::thread::spawn(move || copyio(&mut s, &mut f_stdout));
+    if disconnected.load(Ordering::SeqCst) {
+        eprintln!("disconnected from the shell");
+        return EXIT_ERROR;
+    }
+    match status {
+        Ok(Message::ExitStatus(code)) => {
+            eprintln!("shell exited with status {}", code);
+            code
+        }
+        Ok(Message::ExitSignal(signal)) => {
+            eprintln!("shell killed by signal {}", signal);
+            128 + signal
+        }
+        Ok(msg) => {
+            eprintln!("Error copy: {}", protocol::unexpected(&msg));
+            EXIT_ERROR
+        }
+        Err(e) => {
+            eprintln!("Error copy: {}", e);
+            EXIT_ERROR
+        }
+    }
+}
 
-    let _res = child.join();
-    if raw {
-        setup_sane(l_stdin, &sane_termios).unwrap();
+/// sends the size of the local terminal on every SIGWINCH until the signals
+/// are closed
+fn forward_resizes(mut signals: Signals, channel: &mut Channel) {
+    for _ in signals.forever() {
+        if let Some((Width(cols), Height(rows))) = terminal_size() {
+            if write_message(channel, &Message::Resize { cols, rows }).is_err() {
+                return;
+            }
+        }
     }
 }
 
-fn copyio(rin: &mut dyn Read, rout: &mut dyn Write) {
-    let mut cr = Crypto::new(KEY, IV).unwrap();
+/// key detaching from a shell, ctrl-]
+const DETACH_KEY: u8 = 0x1d;
+/// time a detached shell gets to exit after the hangup
+const HANGUP_TIMEOUT: Duration = Duration::from_secs(5);
 
-    let _br = &match cr.copy(rin, rout) {
-        Ok(b) => b,
-        Err(e) => {
-            println!("Error copy: {}", e);
+/// starts an escape at the start of a line
+const ESCAPE_CHAR: u8 = b'~';
+/// ctrl-z
+const SUSPEND_KEY: u8 = 0x1a;
+
+const ESCAPE_HELP: &str = "\
+supported escapes:\r
+~.   disconnect from the shell\r
+~^Z  suspend the server\r
+~B   send a break (SIGINT) to the remote foreground process\r
+~T   send SIGTERM to the remote foreground process\r
+~K   send SIGKILL to the remote foreground process\r
+~s   print statistics of the shell\r
+~?   print this help\r
+~~   send the escape character\r
+";
+
+/// counters of a shell for the ~s escape
+struct ShellStats {
+    channel: u32,
+    started: Instant,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+/// local output of a shell, counts the received bytes
+struct ShellOutput(Arc<ShellStats>);
+
+impl Write for ShellOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = io::stdout().write(buf)?;
+        self.0.received.fetch_add(len as u64, Ordering::SeqCst);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// local input of a shell, it ends on the detach key, on the escape ~.,
+/// at the end of stdin or when stop is set. stdin stays open for the console
+struct ShellInput {
+    fd: RawFd,
+    stop: Arc<AtomicBool>,
+    detached: bool,
+    /// channel of the shell for the escapes, writes are never interleaved
+    /// with the input
+    control: Channel,
+    disconnected: Arc<AtomicBool>,
+    /// suspended with the server, None without raw mode
+    raw_mode: Option<Arc<RawMode>>,
+    stats: Arc<ShellStats>,
+    line_start: bool,
+    escape: bool,
+    /// input waiting to be sent
+    pending: VecDeque<u8>,
+}
+
+impl ShellInput {
+    /// handles one byte of the local input
+    fn input(&mut self, b: u8) {
+        if self.escape {
+            self.escape = false;
+            match b {
+                b'.' => {
+                    self.disconnected.store(true, Ordering::SeqCst);
+                    self.detached = true;
+                    // a hung shell does not end its output, stop waiting for it
+                    self.control.close();
+                }
+                SUSPEND_KEY => self.suspend(),
+                b'B' => self.signal(libc::SIGINT),
+                b'T' => self.signal(libc::SIGTERM),
+                b'K' => self.signal(libc::SIGKILL),
+                b's' => self.print_stats(),
+                b'?' => print_flush(ESCAPE_HELP),
+                ESCAPE_CHAR => self.send(ESCAPE_CHAR),
+                _ => {
+                    self.send(ESCAPE_CHAR);
+                    self.send(b);
+                }
+            }
             return;
         }
-    };
-}
+        if b == DETACH_KEY {
+            self.detached = true;
+        } else if self.line_start && b == ESCAPE_CHAR {
+            self.escape = true;
+        } else {
+            self.send(b);
+        }
+    }
 
-fn setup_raw(fd: RawFd) -> io::Result<termios::Termios> {
-    let mut termios = Termios::from_fd(fd)?;
+    fn send(&mut self, b: u8) {
+        self.pending.push_back(b);
+        self.line_start = b == b'\r' || b == b'\n';
+    }
+
+    fn signal(&mut self, signal: i32) {
+        if let Err(e) = write_message(&mut self.control, &Message::Signal(signal)) {
+            print_flush(&format!("could not send signal: {}\r\n", e));
+        }
+    }
 
-    let stermios = termios;
+    /// stops the server like ctrl-z, the terminal is restored meanwhile
+    fn suspend(&mut self) {
+        if let Some(mode) = &self.raw_mode {
+            let _ = mode.suspend();
+        }
+        print_flush("\r\n");
+        unsafe { libc::kill(libc::getpid(), libc::SIGTSTP) };
+        // continued by fg
+        if let Some(mode) = &self.raw_mode {
+            let _ = mode.resume();
+        }
+    }
 
-    cfmakeraw(&mut termios);
-    tcsetattr(fd, TCSANOW, &mut termios)?;
+    /// waits up to HANGUP_TIMEOUT for the output of the shell to end,
+    /// returns false when it did not
+    fn wait_stop(&self) -> bool {
+        let started = Instant::now();
+        while !self.stop.load(Ordering::SeqCst) {
+            if started.elapsed() >= HANGUP_TIMEOUT {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        true
+    }
 
-    Ok(stermios)
+    fn print_stats(&self) {
+        let elapsed = Duration::from_secs(self.stats.started.elapsed().as_secs());
+        print_flush(&format!(
+            "\r\nchannel {}: {} bytes sent, {} bytes received in {}\r\n",
+            self.stats.channel,
+            self.stats.sent.load(Ordering::SeqCst),
+            self.stats.received.load(Ordering::SeqCst),
+            humantime::format_duration(elapsed)
+        ));
+    }
 }
 
-fn setup_sane(fd: RawFd, &termios: &termios::Termios) -> io::Result<()> {
-    tcsetattr(fd, TCSANOW, &termios)?;
+fn print_flush(msg: &str) {
+    let _ = write_flush(&mut io::stderr(), msg.as_bytes());
+}
 
-    Ok(())
+impl Read for ShellInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.pending.is_empty() {
+                let len = buf.len().min(self.pending.len());
+                for (b, v) in buf.iter_mut().zip(self.pending.drain(..len)) {
+                    *b = v;
+                }
+                self.stats.sent.fetch_add(len as u64, Ordering::SeqCst);
+                return Ok(len);
+            }
+            if self.detached || self.stop.load(Ordering::SeqCst) {
+                return Ok(0);
+            }
+            let mut pfd = libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // wake up regularly to notice stop
+            match unsafe { libc::poll(&mut pfd, 1, 100) } {
+                0 => continue,
+                n if n < 0 => match io::Error::last_os_error() {
+                    // a SIGWINCH interrupted the poll
+                    e if e.kind() == io::ErrorKind::Interrupted => continue,
+                    e => return Err(e),
+                },
+                _ => {}
+            }
+            let len =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len < 0 {
+                match io::Error::last_os_error() {
+                    e if e.kind() == io::ErrorKind::Interrupted => continue,
+                    e => return Err(e),
+                }
+            }
+            if len == 0 {
+                // the end of stdin ends the input once pending is sent
+                self.detached = true;
+                continue;
+            }
+            for &b in &buf[..len as usize] {
+                self.input(b);
+                if self.detached {
+                    break;
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,82 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! sessions of the persistent server mode
+//!
+//! every authenticated client is registered as a numbered session. the
+//! commands on a session run on their own mux channels, so a session can
+//! be used while the listener registers new clients.
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use cryptolib::mux::Mux;
+use cryptolib::protocol::Compression;
+
+pub struct Session {
+    pub number: usize,
+    pub addr: SocketAddr,
+    pub remote_id: String,
+    pub connected: SystemTime,
+    /// best compression the client supports
+    pub compression: Compression,
+    pub mux: Mux,
+}
+
+#[derive(Default)]
+pub struct Sessions {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next: usize,
+    sessions: BTreeMap<usize, Arc<Session>>,
+}
+
+impl Sessions {
+    /// registers a new session and returns its number
+    pub fn add(
+        &self,
+        addr: SocketAddr,
+        remote_id: String,
+        compression: Compression,
+        mux: Mux,
+    ) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next += 1;
+        let number = inner.next;
+        inner.sessions.insert(
+            number,
+            Arc::new(Session {
+                number,
+                addr,
+                remote_id,
+                connected: SystemTime::now(),
+                compression,
+                mux,
+            }),
+        );
+        number
+    }
+
+    pub fn get(&self, number: usize) -> Option<Arc<Session>> {
+        self.inner.lock().unwrap().sessions.get(&number).cloned()
+    }
+
+    pub fn remove(&self, number: usize) -> Option<Arc<Session>> {
+        self.inner.lock().unwrap().sessions.remove(&number)
+    }
+
+    /// lists the sessions, dropping the ones closed by their clients
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.sessions.retain(|_, session| !session.mux.is_closed());
+        inner.sessions.values().cloned().collect()
+    }
+}
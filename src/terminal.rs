@@ -0,0 +1,122 @@
+// Copyright (c) 2021 asisdrico <asisdrico@outlook.com>
+//
+// Licensed under the MIT license
+// <LICENSE or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! raw mode of the operator terminal
+//!
+//! the terminal is restored when the RawMode guard is dropped, which also
+//! happens while unwinding from a panic. restore_on_exit covers the exits
+//! that skip the guard, a panic in another thread and the signals
+//! terminating the server. while a command of the console runs, SIGINT is
+//! handed to catch_interrupts instead.
+use std::io;
+use std::os::unix::io::RawFd;
+use std::panic;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use signal_hook::iterator::Signals;
+use termios::{cfmakeraw, tcsetattr, Termios, TCSANOW};
+
+/// modes to restore while a terminal is in raw mode
+static SAVED: Mutex<Option<(RawFd, Termios)>> = Mutex::new(None);
+/// receives SIGINT instead of the default handler, see catch_interrupts
+static INTERRUPTS: Mutex<Option<Sender<()>>> = Mutex::new(None);
+
+/// keeps a terminal in raw mode until it is dropped
+pub struct RawMode {
+    fd: RawFd,
+    saved: Termios,
+}
+
+impl RawMode {
+    /// switches the terminal fd to raw mode
+    pub fn enable(fd: RawFd) -> io::Result<RawMode> {
+        let saved = Termios::from_fd(fd)?;
+        let mode = RawMode { fd, saved };
+        mode.resume()?;
+        *SAVED.lock().unwrap_or_else(|e| e.into_inner()) = Some((fd, saved));
+        Ok(mode)
+    }
+
+    /// restores the saved modes until resume, while the server is suspended
+    pub fn suspend(&self) -> io::Result<()> {
+        tcsetattr(self.fd, TCSANOW, &self.saved)
+    }
+
+    /// switches back to raw mode
+    pub fn resume(&self) -> io::Result<()> {
+        let mut raw = self.saved;
+        cfmakeraw(&mut raw);
+        tcsetattr(self.fd, TCSANOW, &raw)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = self.suspend();
+        *SAVED.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+}
+
+pub fn is_terminal(fd: RawFd) -> bool {
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+/// restores a terminal in raw mode on a panic and when the server is
+/// terminated by SIGINT, SIGTERM, SIGHUP or SIGQUIT
+pub fn restore_on_exit() -> io::Result<()> {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore();
+        hook(info);
+    }));
+
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP, SIGQUIT])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGINT && interrupt() {
+                continue;
+            }
+            restore();
+            let _ = signal_hook::low_level::emulate_default_handler(signal);
+        }
+    });
+    Ok(())
+}
+
+/// hands SIGINT to the returned receiver instead of terminating the server
+/// until release_interrupts, the receiver ends then
+pub fn catch_interrupts() -> Receiver<()> {
+    let (tx, rx) = mpsc::channel();
+    *INTERRUPTS.lock().unwrap_or_else(|e| e.into_inner()) = Some(tx);
+    rx
+}
+
+/// restores the default handling of SIGINT
+pub fn release_interrupts() {
+    *INTERRUPTS.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// passes SIGINT to catch_interrupts, false when nobody catches it
+fn interrupt() -> bool {
+    match INTERRUPTS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_ref()
+    {
+        Some(tx) => tx.send(()).is_ok(),
+        None => false,
+    }
+}
+
+fn restore() {
+    if let Some((fd, saved)) = SAVED.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        let _ = tcsetattr(fd, TCSANOW, &saved);
+    }
+}